@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+// Parses the elapsed time prefix of a ct-ng log line, e.g. `[01:23]` or
+// `[1:01:23]`, returning it along with the rest of the line
+fn parse_elapsed(line: &str) -> Option<(Duration, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let (stamp, rest) = rest.split_once(']')?;
+
+    let mut secs = 0;
+    for part in stamp.split(':') {
+        secs = secs * 60 + part.trim().parse::<u64>().ok()?;
+    }
+    Some((Duration::from_secs(secs), rest))
+}
+
+// Extracts the step name out of `[INFO ]  Step N/M: <step>`
+fn parse_step_name(rest: &str) -> Option<&str> {
+    let (_, step) = rest.split_once("Step ")?;
+    let (counter, name) = step.split_once(": ")?;
+    let (n, m) = counter.split_once('/')?;
+    if n.parse::<u32>().is_err() || m.parse::<u32>().is_err() {
+        return None;
+    }
+    Some(name.trim())
+}
+
+pub fn parse_ct_log_step(log: &str) -> Vec<(String, Duration)> {
+    let mut steps: Vec<(String, Duration)> = Vec::new();
+    let mut last_seen = Duration::ZERO;
+
+    for line in log.lines() {
+        let Some((elapsed, rest)) = parse_elapsed(line) else {
+            continue;
+        };
+        last_seen = elapsed;
+        if let Some(name) = parse_step_name(rest) {
+            steps.push((name.to_string(), elapsed));
+        }
+    }
+
+    // Each step lasts until the next one starts, the last one until the end
+    // of the log
+    let ends: Vec<Duration> = steps.iter()
+        .skip(1)
+        .map(|(_, start)| *start)
+        .chain(std::iter::once(last_seen))
+        .collect();
+
+    steps.into_iter()
+        .zip(ends)
+        .map(|((name, start), end)| (name, end.saturating_sub(start)))
+        .collect()
+}
+
+const FAILURE_HINTS: &[(&str, &str)] = &[
+    ("C compiler cannot create executables", "The host compiler doesn't work, check that gcc and make are installed"),
+    ("Unable to fetch", "A source download failed, check your network connection or mirror settings"),
+    ("No space left on device", "The disk is full, free up some space and try again"),
+];
+
+pub fn failure_hint(log: &str) -> Option<&'static str> {
+    log.lines()
+        .rev()
+        .find_map(|line| FAILURE_HINTS.iter()
+            .find(|(pattern, _)| line.contains(pattern))
+            .map(|(_, hint)| *hint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{failure_hint, parse_ct_log_step};
+    use std::time::Duration;
+
+    #[test]
+    fn steps_with_durations() {
+        let log = "\
+[00:00] [INFO ]  Performing some trivial sanity checks
+[00:01] [INFO ]  Step 1/3: Retrieving needed toolchain components' tarballs
+[00:30] [EXTRA]    Retrieving 'gcc-15'
+[02:10] [INFO ]  Step 2/3: Installing binutils for host
+[1:02:10] [INFO ]  Step 3/3: Installing final gcc compiler
+[1:40:00] [INFO ]  Finishing installation
+";
+        assert_eq!(parse_ct_log_step(log), vec![
+            ("Retrieving needed toolchain components' tarballs".to_string(), Duration::from_secs(129)),
+            ("Installing binutils for host".to_string(), Duration::from_secs(3600)),
+            ("Installing final gcc compiler".to_string(), Duration::from_secs(37 * 60 + 50)),
+        ]);
+    }
+
+    #[test]
+    fn lines_without_timestamps_are_ignored() {
+        let log = "\
+[INFO ]  Step 1/2: Installing binutils for host
+garbage
+[00:05] [INFO ]  Step 2/2: Installing final gcc compiler
+[00:07] [INFO ]  Done
+";
+        assert_eq!(parse_ct_log_step(log), vec![
+            ("Installing final gcc compiler".to_string(), Duration::from_secs(2)),
+        ]);
+    }
+
+    #[test]
+    fn failure_hints() {
+        let log = "\
+[ERROR]    configure: error: C compiler cannot create executables
+[ERROR]  >>
+";
+        assert!(failure_hint(log).unwrap().contains("gcc and make"));
+        assert!(failure_hint("[ERROR]  error: Unable to fetch https://example.com/gcc.tar.xz").unwrap().contains("mirror"));
+        assert!(failure_hint("cp: write error: No space left on device").unwrap().contains("disk"));
+        assert_eq!(failure_hint("[ERROR]  something else entirely"), None);
+    }
+}
@@ -1,45 +1,141 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::engine::{ArgValueCompleter, CompletionCandidate, ValueCompleter};
+use std::path::{Path, PathBuf};
 use std::env;
 use std::fs;
-use std::ffi::CString;
+use std::ffi::{CString, OsStr};
 use std::fs::OpenOptions;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use serde::{Serialize, Deserialize};
 
-use std::io::Write;
-use log::{LevelFilter, info, debug};
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{LevelFilter, debug};
 
-use anyhow::{bail, Context, Result};
-use std::process::Command;
+use anyhow::{anyhow, bail, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
 
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+
+mod ct_log;
+mod printer;
 mod triple;
-use triple::Triple;
+use printer::OutputFormat;
+use triple::{Arch, BareMetalLibc, Bits, Triple};
 
 #[derive(Debug, Parser)]
 struct Args {
     #[arg(short, long, default_value = "info")]
     loglevel: LevelFilter,
+    /// Output format of commands that print data
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    format: OutputFormat,
+    /// Environment variable set for every ct-ng invocation, e.g. `CT_ALLOW_BUILD_AS_ROOT=y`, can be repeated
+    #[arg(long = "ct-ng-args", global = true, value_name = "KEY=VALUE", value_parser = parse_env_assignment)]
+    ct_ng_extra_args: Vec<(String, String)>,
     #[command(subcommand)]
     cmd: Commands,
 }
 
+// Parsed once at startup, the size doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Setup directory structure
-    Setup,
+    Setup {
+        /// Directory where toolchains are built and installed
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Directory where downloaded sources are cached
+        #[arg(long)]
+        cache_dir: Option<PathBuf>,
+        /// Use system-wide /var/lib/chained and /var/cache/chained directories
+        #[arg(long)]
+        shared: bool,
+        /// Only check that an existing setup is complete, without creating anything
+        #[arg(long, conflicts_with_all = ["data_dir", "cache_dir", "shared"])]
+        check: bool,
+    },
     /// Operations on a toolchain
     Toolchain {
         /// Target triple
+        #[arg(add = ArgValueCompleter::new(TripleCompleter))]
         target: Triple,
         #[command(subcommand)]
         cmd: TargetCmd,
     },
     /// Show current config
     Show,
+    /// List configured toolchains
+    List {
+        /// Output a JSON array, same as `--format json`
+        #[arg(long)]
+        json: bool,
+        /// Describe each toolchain's target
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Print shell commands setting up the environment for cross compilation
+    Env {
+        /// Target triple
+        #[arg(add = ArgValueCompleter::new(TripleCompleter))]
+        target: Triple,
+        /// Shell syntax of the output
+        #[arg(long = "shell", value_enum, default_value = "posix")]
+        shell: EnvFormat,
+    },
+    /// Print the full path of a cross tool, e.g. `gcc`
+    Which {
+        /// Tool name, with or without the target triple prefix
+        tool: String,
+        /// Target triple
+        #[arg(add = ArgValueCompleter::new(TripleCompleter))]
+        target: Triple,
+    },
+    /// Read or write individual config fields
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCmd,
+    },
+    /// Update the config file to the current format
+    Migrate {
+        /// Only print what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Remove everything that chained has installed
-    Remove,
+    Remove {
+        /// Keep the config file
+        #[arg(long)]
+        keep_config: bool,
+        /// Only print what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCmd {
+    /// Print the value of a field, e.g. `cache_dir` or `toolchain.0.gcc_src`
+    Get {
+        key: String,
+    },
+    /// Set the value of a field
+    Set {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        value: String,
+    },
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 enum TargetCmd {
     /// Configure, download and build a target toolchain
@@ -50,19 +146,442 @@ enum TargetCmd {
         /// Inspect config with `nconfig`
         #[arg(short, long)]
         inspect: bool,
+        /// Additional CFLAGS for building the target sysroot
+        #[arg(long, allow_hyphen_values = true)]
+        target_cflags: Option<String>,
+        /// Additional LDFLAGS for building the target sysroot
+        #[arg(long, allow_hyphen_values = true)]
+        target_ldflags: Option<String>,
+        /// Start from a crosstool-ng sample, with chained's settings and the other flags on top
+        #[arg(long, alias = "ct-sample")]
+        from_sample: Option<String>,
+        /// Don't pass `--enable-host-shared` to GCC, building libgccjit as a static library
+        ///
+        /// This may not work with all GCC configurations, and the resulting
+        /// libgccjit.a is less portable than the shared library.
+        #[arg(long)]
+        no_host_shared: bool,
+        /// Build the C++ frontend and libstdc++
+        ///
+        /// `--enable-c++ true` is a shorthand for `--languages c,c++`, and
+        /// `--enable-c++ false` for `--languages c`.
+        #[arg(long = "enable-c++", default_value_t = true, action = clap::ArgAction::Set)]
+        enable_cxx: bool,
+        /// Source URL or local path for binutils
+        #[arg(long)]
+        binutils_src: Option<String>,
+        /// Source URL or local path for glibc, only used for glibc-based toolchains
+        #[arg(long)]
+        glibc_src: Option<String>,
+        /// Download sources in parallel, with 4 downloads at a time unless specified
+        ///
+        /// Released crosstool-ng versions download one source at a time and
+        /// ignore this, it only does something with a ct-ng that has the option.
+        #[arg(long, num_args = 0..=1, default_missing_value = "4", value_name = "N")]
+        parallel_download: Option<usize>,
+        /// Maximum log level of crosstool-ng, defaults to whatever ct-ng uses
+        #[arg(long, value_enum)]
+        ct_log_level: Option<CtNgLogLevel>,
+        /// Mirror for source downloads, the `CHAINED_MIRROR` environment variable is used as a fallback
+        #[arg(long)]
+        ct_mirror: Option<String>,
+        /// Use newlib as the C library of a bare-metal toolchain
+        #[arg(long)]
+        with_newlib: bool,
+        /// Config fragment appended to the generated defconfig, can be repeated
+        #[arg(long = "ct-config-fragment")]
+        ct_config_fragments: Vec<PathBuf>,
+        /// GDB version to include in the toolchain, defaults to whatever ct-ng uses
+        #[arg(long, alias = "ct-gdb-version", conflicts_with = "no_gdb")]
+        gdb_version: Option<String>,
+        /// Don't build GDB, which saves a good chunk of build time
+        #[arg(long)]
+        no_gdb: bool,
+        /// Build strace for the target and install it into the sysroot
+        #[arg(long)]
+        with_strace: bool,
+        /// strace version to build, defaults to whatever ct-ng uses
+        #[arg(long, requires = "with_strace")]
+        strace_version: Option<String>,
+        /// Tool crosstool-ng downloads sources with
+        #[arg(long, value_enum)]
+        downloader: Option<CtDownloader>,
+        /// Additional options for wget, e.g. proxy settings
+        #[arg(long, allow_hyphen_values = true)]
+        wget_opts: Option<String>,
+        /// Additional options for curl, e.g. proxy settings
+        #[arg(long, allow_hyphen_values = true)]
+        curl_opts: Option<String>,
+        /// Triple of the machine the toolchain is built on, defaults to what ct-ng detects
+        #[arg(long, alias = "canadian-build-triple")]
+        build_triple: Option<Triple>,
+        /// Triple of the machine the toolchain runs on, which makes this a Canadian cross
+        #[arg(long)]
+        host_triple: Option<Triple>,
+        /// Sysroot for the --host-triple machine, used for its pkg-config files
+        #[arg(long, alias = "ct-canadian-host-pkg-config", requires = "host_triple")]
+        host_sysroot: Option<PathBuf>,
+        /// Number of make jobs inside each ct-ng step, independent of `compile --jobs`
+        #[arg(long, alias = "ct-parallel-jobs")]
+        ct_jobs: Option<usize>,
+        /// Save the state after every build step, so single steps can be rerun with `step`
+        #[arg(long, alias = "ct-save-steps")]
+        step_by_step: bool,
+        /// Directory of local patches applied on top of the bundled ones, copied into the base directory
+        #[arg(long)]
+        ct_overlay: Option<PathBuf>,
+        /// Additional GCC configure flag, appended to the ones chained passes, can be repeated
+        #[arg(long, allow_hyphen_values = true)]
+        gcc_config_extra: Vec<String>,
+        /// Additional C library configure flag, can be repeated
+        #[arg(long, alias = "ct-libc-extra-config", allow_hyphen_values = true)]
+        libc_extra_config: Vec<String>,
+        /// Linux kernel version, also used for the headers unless --kernel-headers-version is given
+        #[arg(long)]
+        kernel_version: Option<String>,
+        /// Linux kernel headers version, also used for the kernel unless --kernel-version is given
+        #[arg(long, alias = "ct-kernel-headers-version")]
+        kernel_headers_version: Option<String>,
+        /// Build GCC with debug info and internal checks, for working on the compiler itself
+        ///
+        /// This roughly doubles the size of the toolchain binaries and makes
+        /// the build significantly slower.
+        #[arg(long, alias = "with-debug-info")]
+        debug_info: bool,
+        /// Never download anything, all sources have to already be in the cache directory
+        #[arg(long, alias = "ct-use-local-tarballs-only")]
+        offline: bool,
+        /// Version of a host library ct-ng builds for the toolchain, as LIB=VERSION, can be repeated
+        ///
+        /// LIB is one of expat, ncurses, zlib, gettext or libiconv.
+        #[arg(long, alias = "ct-host-lib-version", value_name = "LIB=VERSION", value_parser = parse_host_lib_version)]
+        host_lib_version: Vec<(String, String)>,
+        /// Additional binutils configure flag, can be repeated
+        ///
+        /// Passed through ct-ng's CT_BINUTILS_EXTRA_CONFIG_ARRAY, e.g.
+        /// `--enable-targets=all` for an objdump that handles every
+        /// architecture, or `--enable-gold` for the gold linker.
+        #[arg(long, alias = "ct-binutils-extra-config", allow_hyphen_values = true)]
+        binutils_extra_config: Vec<String>,
+        /// Threading model of GCC, defaults to posix on Linux and none on bare-metal targets
+        #[arg(long, alias = "ct-threads", value_enum)]
+        threads: Option<GccThreads>,
+        /// Alternative prefix for the installed tools, e.g. `arm-linux` for `arm-linux-gcc`
+        #[arg(long, alias = "ct-target-prefix")]
+        target_alias: Option<String>,
+        /// Build the target libraries with _FORTIFY_SOURCE at the given level
+        #[arg(long, alias = "ct-fortify-source", value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=3))]
+        fortify_source: Option<u8>,
+        /// Build the target libraries with the given stack protector
+        #[arg(long, value_enum)]
+        stack_protector: Option<StackProtector>,
+        /// Link the toolchain binaries statically, so they run on hosts with other library versions
+        #[arg(long = "static", alias = "ct-static-toolchain")]
+        static_toolchain: bool,
+        /// Build the cross-compiler itself with LTO, which makes it faster
+        ///
+        /// This needs an LTO-capable host compiler and a lot more memory and
+        /// time during the build.
+        #[arg(long, alias = "ct-enable-lto")]
+        lto: bool,
+        /// Directory between the target directory and the sysroot, for multilib layouts
+        #[arg(long, alias = "ct-with-sysroot")]
+        sysroot_dir_prefix: Option<String>,
+        /// Suffix of the sysroot directory, for multilib layouts
+        #[arg(long)]
+        sysroot_suffix: Option<String>,
+        /// Only change the GCC source of an existing toolchain, without rebuilding it
+        #[arg(long, value_name = "GCC_SRC", conflicts_with = "gcc_src")]
+        update_gcc_src: Option<String>,
+        /// Let crosstool-ng build as root, e.g. inside a Docker container
+        #[arg(long, alias = "ct-allow-root")]
+        allow_root: bool,
+        /// Directory of local patches used in place, so edits are picked up by the next build
+        #[arg(long, alias = "ct-overlay-dir", conflicts_with = "ct_overlay")]
+        local_patch_dir: Option<PathBuf>,
+        /// Optimization flags for all target code, appended to --target-cflags, e.g. `-mtune=cortex-a53`
+        #[arg(long, alias = "ct-target-opt-cflags", allow_hyphen_values = true)]
+        target_opt_cflags: Option<String>,
+        /// Enable GCC's Graphite loop optimizations, which need ISL
+        #[arg(long, alias = "ct-with-cloog")]
+        with_graphite: bool,
+        /// ISL version to build, defaults to whatever ct-ng uses
+        #[arg(long, requires = "with_graphite")]
+        isl_version: Option<String>,
+        /// Build the Fortran frontend and libgfortran
+        #[arg(long, alias = "ct-with-fortran")]
+        with_fortran: bool,
+        /// Build ThreadSanitizer support, shorthand for `--sanitizer thread`
+        #[arg(long, alias = "ct-thread-sanitizer")]
+        with_tsan: bool,
+        /// Sanitizer runtime to build, can be repeated
+        ///
+        /// GCC builds all of its sanitizer runtimes at once, so this mostly
+        /// decides what chained warns about. Cross-compiled sanitizers are
+        /// experimental and only work on Linux targets.
+        #[arg(long, value_enum)]
+        sanitizer: Vec<Sanitizer>,
+        /// Build a multilib toolchain, e.g. with both 32-bit and 64-bit libraries
+        #[arg(long, alias = "ct-enable-multilib")]
+        multilib: bool,
+        /// Build glibc with locale and NLS support, defaults to whatever ct-ng uses
+        #[arg(long, alias = "ct-libc-locales", action = clap::ArgAction::Set)]
+        libc_locales: Option<bool>,
+        /// Build glibc with NPTL, `--nptl false` is for single-threaded boards
+        #[arg(long, alias = "ct-enable-nptl", default_value_t = true, action = clap::ArgAction::Set)]
+        nptl: bool,
+        /// Let ct-ng strip the installed toolchain executables, which makes them a lot smaller
+        #[arg(long, alias = "ct-strip-host-toolchain-executables", conflicts_with = "debug_info")]
+        strip_host: bool,
+        /// Pause the build and open a shell before the given step, can be repeated
+        #[arg(long, alias = "ct-debug-shell", value_name = "STEP")]
+        debug_at_step: Vec<String>,
+        /// C++ standard library to build against, only libstdc++ for now
+        ///
+        /// libc++ needs an LLVM based toolchain, which chained can't build yet.
+        #[arg(long, alias = "ct-cxx-abi", value_enum)]
+        cxx_abi: Option<CxxAbi>,
     },
     /// Show information about the toolchain
-    Show,
+    Show {
+        /// Show the flags GCC was configured with
+        #[arg(long)]
+        flags: bool,
+        /// Show the environment variables that would be set in a shell
+        #[arg(long)]
+        env: bool,
+        /// Print the crosstool-ng .config, without comments and disabled options
+        #[arg(long)]
+        ct_config: bool,
+        /// With --ct-config, print the whole file
+        #[arg(long, requires = "ct_config")]
+        all: bool,
+        /// Print the value of a single crosstool-ng option
+        #[arg(long, value_name = "KEY")]
+        ct_option: Option<String>,
+        /// Show the hardening options of the target libraries
+        #[arg(long)]
+        security: bool,
+    },
     /// Download everything required to compile
     Download,
     /// Compile the toolchain
-    Compile,
+    Compile {
+        /// Number of parallel jobs, defaults to `CT_JOBS` or the number of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Change the maximum log level of crosstool-ng before building
+        #[arg(long, value_enum)]
+        ct_log_level: Option<CtNgLogLevel>,
+        /// Color the build output even when it doesn't go to a terminal
+        ///
+        /// Coloring is the default when it does. Logs are never colored.
+        #[arg(long, overrides_with = "no_color")]
+        color: bool,
+        /// Don't color the build output
+        #[arg(long)]
+        no_color: bool,
+        /// Only run a single build step, needs a toolchain added with --step-by-step
+        #[arg(long, alias = "ct-only-step", value_name = "STEP", conflicts_with = "jobs")]
+        only_step: Option<String>,
+    },
     /// Reconfigure the toolchain with nconfig
     Reconfigure,
+    /// Clean the build directory, regenerate the configuration and compile from scratch
+    Rebuild {
+        /// Review the configuration with nconfig before compiling
+        #[arg(long)]
+        inspect: bool,
+        /// Also remove everything installed in the prefix directory
+        #[arg(long)]
+        also_clean_prefix: bool,
+    },
+    /// Run a single build step, see `list-steps`
+    Step {
+        /// Name of the step
+        step_name: String,
+    },
+    /// List the build steps of crosstool-ng
+    ListSteps,
+    /// Compare the crosstool-ng configuration with another toolchain
+    Diff {
+        /// Toolchain to compare against
+        #[arg(add = ArgValueCompleter::new(TripleCompleter))]
+        other: Triple,
+        /// Also print options that are the same in both
+        #[arg(long)]
+        full: bool,
+    },
+    /// Show how long each step of the last build took
+    BuildStats,
+    /// Reload the crosstool-ng sample defaults, review them and recompile
+    UpdateSample,
     /// Start a shell with environment set up for cross compilation
-    Shell,
+    Shell {
+        /// Text prepended to the shell prompt, `{triple}` is replaced with the target triple
+        #[arg(long, default_value = "[{triple}]")]
+        prompt_suffix: String,
+        /// Don't modify the shell prompt
+        #[arg(long)]
+        no_prompt_change: bool,
+        /// Fail instead of warning when the toolchain isn't compiled yet
+        #[arg(long)]
+        require_installed: bool,
+    },
+    /// Run a command with environment set up for cross compilation
+    Run {
+        /// Command and its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        cmd: Vec<String>,
+    },
+    /// Persistently set an environment variable for the toolchain
+    AddEnv {
+        key: String,
+        #[arg(allow_hyphen_values = true)]
+        value: String,
+    },
+    /// Remove a persistent environment variable from the toolchain
+    RemoveEnv {
+        key: String,
+    },
+    /// Print shell commands setting up the environment for cross compilation
+    Env {
+        /// Shell syntax of the output
+        #[arg(long = "shell", value_enum, default_value = "posix")]
+        shell: EnvFormat,
+    },
+    /// Print a `.cargo/config.toml` snippet for using the toolchain
+    CargoConfig,
+    /// Look at the layout of the installed toolchain
+    Inspect {
+        /// Tool used for inspecting, defaults to `tree` if installed, otherwise `ls`
+        #[arg(value_enum)]
+        tool: Option<InspectTool>,
+    },
+    /// Generate a Rust JSON target specification
+    JsonSpec {
+        /// Additional arguments passed to the linker before everything else
+        #[arg(long, allow_hyphen_values = true)]
+        extra_pre_link_args: Vec<String>,
+        /// Additional arguments passed to the linker after everything else
+        #[arg(long, allow_hyphen_values = true)]
+        extra_post_link_args: Vec<String>,
+        /// Environment variables set for the linker, as KEY=VALUE
+        #[arg(long)]
+        link_env: Vec<String>,
+    },
+    /// Pack the toolchain into a self-contained SDK tarball
+    Pack {
+        /// Output tarball, compressed based on the extension
+        output: PathBuf,
+        /// Make the environment setup script find the SDK relative to itself
+        #[arg(long)]
+        relocatable: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum, Serialize, Deserialize, strum::Display, strum::VariantArray)]
+#[strum(serialize_all = "UPPERCASE")]
+enum CtNgLogLevel {
+    Error,
+    Warn,
+    Info,
+    Extra,
+    All,
+    Debug,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "UPPERCASE")]
+enum CtDownloader {
+    Wget,
+    Curl,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+enum GccThreads {
+    None,
+    Posix,
+    Win32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum, Serialize, Deserialize, strum::Display)]
+enum CxxAbi {
+    #[value(name = "libstdc++")]
+    #[serde(rename = "libstdc++")]
+    #[strum(serialize = "libstdc++")]
+    Libstdcxx,
+    #[value(name = "libc++")]
+    #[serde(rename = "libc++")]
+    #[strum(serialize = "libc++")]
+    Libcxx,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+enum StackProtector {
+    None,
+    Basic,
+    Strong,
+    All,
+}
+
+impl StackProtector {
+    fn cflag(self) -> &'static str {
+        match self {
+            Self::None => "-fno-stack-protector",
+            Self::Basic => "-fstack-protector",
+            Self::Strong => "-fstack-protector-strong",
+            Self::All => "-fstack-protector-all",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+enum Sanitizer {
+    Address,
+    Thread,
+    Undefined,
+    Leak,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum EnvFormat {
+    Bash,
+    Fish,
+    Posix,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum InspectTool {
+    /// Print a tree with `tree`
+    Tree,
+    /// List the prefix with `ls -la`
+    Ls,
+    /// Open in a file manager with `xdg-open`
+    Xdg,
 }
 
+// Variables kept when exec-ing into a shell with an explicit environment
+const PASSTHROUGH_ENV: &[&str] = &[
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "TERM",
+    "COLORTERM",
+    "CARGO_HOME",
+    "RUSTUP_HOME",
+    "XDG_RUNTIME_DIR",
+    "DBUS_SESSION_BUS_ADDRESS",
+];
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Toolchain {
     triple: Triple,
@@ -70,176 +589,1840 @@ struct Toolchain {
     basedir: PathBuf,
     json_spec: PathBuf,
     prefix: PathBuf,
+    target_cflags: Option<String>,
+    target_ldflags: Option<String>,
+    #[serde(default = "default_true")]
+    host_shared: bool,
+    #[serde(default = "default_true")]
+    cxx_enabled: bool,
+    binutils_src: Option<String>,
+    glibc_src: Option<String>,
+    parallel_downloads: Option<usize>,
+    #[serde(default)]
+    custom_env: Vec<(String, String)>,
+    ct_log_level: Option<CtNgLogLevel>,
+    ct_mirror: Option<String>,
+    #[serde(default)]
+    ct_config_fragments: Vec<PathBuf>,
+    #[serde(default = "default_true")]
+    gdb_enabled: bool,
+    gdb_version: Option<String>,
+    #[serde(default)]
+    strace_enabled: bool,
+    strace_version: Option<String>,
+    downloader: Option<CtDownloader>,
+    wget_opts: Option<String>,
+    curl_opts: Option<String>,
+    build_triple: Option<Triple>,
+    ct_jobs: Option<usize>,
+    #[serde(default)]
+    save_steps: bool,
+    ct_overlay: Option<PathBuf>,
+    #[serde(default)]
+    gcc_config_extra: Vec<String>,
+    #[serde(default)]
+    libc_extra_config: Vec<String>,
+    kernel_version: Option<String>,
+    kernel_headers_version: Option<String>,
+    #[serde(default)]
+    debug_build: bool,
+    #[serde(default)]
+    offline: bool,
+    #[serde(default)]
+    host_lib_versions: HashMap<String, String>,
+    #[serde(default)]
+    binutils_extra_config: Vec<String>,
+    threads: Option<GccThreads>,
+    target_alias: Option<String>,
+    fortify_source: Option<u8>,
+    stack_protector: Option<StackProtector>,
+    #[serde(default)]
+    static_toolchain: bool,
+    #[serde(default)]
+    lto_enabled: bool,
+    sysroot_dir_prefix: Option<String>,
+    sysroot_suffix: Option<String>,
+    #[serde(default)]
+    allow_root: bool,
+    local_patch_dir: Option<PathBuf>,
+    target_opt_cflags: Option<String>,
+    #[serde(default)]
+    graphite_enabled: bool,
+    isl_version: Option<String>,
+    #[serde(default)]
+    fortran_enabled: bool,
+    #[serde(default)]
+    sanitizers: Vec<Sanitizer>,
+    // Hash of the crosstool_config the .config was last generated from
+    config_hash: Option<String>,
+    #[serde(default)]
+    multilib: bool,
+    libc_locales: Option<bool>,
+    #[serde(default = "default_true")]
+    nptl: bool,
+    #[serde(default)]
+    strip_host: bool,
+    #[serde(default)]
+    debug_pause_steps: Vec<String>,
+    host_triple: Option<Triple>,
+    host_sysroot: Option<PathBuf>,
+    cxx_abi: Option<CxxAbi>,
+    // ct-ng sample the .config was loaded from, to load it again on rebuild
+    from_sample: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl Toolchain {
     pub fn crosstool_config(&self, cfg: &Config) -> String {
         let mut opts = Vec::new();
         self.triple.emit_crosstool_config(&mut opts);
+        self.emit_toolchain_config(cfg, &mut opts);
+        opts.into_iter().map(|v| v + "\n").collect()
+    }
+    // Everything but the target itself, which a sample already picks
+    fn emit_toolchain_config(&self, cfg: &Config, opts: &mut Vec<String>) {
+        self.emit_source_config(cfg, opts);
+
+        if let Some(src) = &self.binutils_src {
+            if let Some(path) = local_source_path(src) {
+                opts.push(String::from("CT_BINUTILS_SRC_CUSTOM=y"));
+                opts.push(format!("CT_BINUTILS_CUSTOM_LOCATION=\"{}\"", path));
+            } else {
+                opts.push(String::from("CT_BINUTILS_SRC_DEVEL=y"));
+                opts.push(format!("CT_BINUTILS_DEVEL_URL=\"{}\"", src));
+            }
+        }
 
+        if !self.binutils_extra_config.is_empty() {
+            opts.push(format!("CT_BINUTILS_EXTRA_CONFIG_ARRAY=\"{}\"", kconfig_escape(&self.binutils_extra_config.join(" "))));
+        }
+
+        if let Some(src) = &self.glibc_src && self.triple.is_glibc() {
+            opts.push(String::from("CT_LIBC_GLIBC_SRC_DEVEL=y"));
+            opts.push(format!("CT_LIBC_GLIBC_DEVEL_URL=\"{}\"", src));
+        }
+
+        let libc_extra_config = self.libc_extra_config();
+        if let Some(key) = self.triple.libc_extra_config_key() && !libc_extra_config.is_empty() {
+            opts.push(format!("{key}=\"{}\"", kconfig_escape(&libc_extra_config.join(" "))));
+        }
+
+        self.emit_jit_config(opts);
+        if !self.cxx_enabled {
+            opts.push(String::from("CT_CC_LANG_CXX=n"));
+        }
+        if self.fortran_enabled {
+            opts.push(String::from("CT_CC_LANG_FORTRAN=y"));
+        }
+        self.emit_gcc_extra_config(opts);
+
+        if let Some(cflags) = self.target_cflags() {
+            opts.push(format!("CT_TARGET_CFLAGS=\"{}\"", cflags));
+        }
+        if let Some(ldflags) = &self.target_ldflags {
+            opts.push(format!("CT_TARGET_LDFLAGS=\"{}\"", ldflags));
+        }
+        // Not in config/global/download.in of any ct-ng release as far as we
+        // know, so defconfig drops these without a word; see the warning in add
+        if let Some(n) = self.parallel_downloads {
+            opts.push(String::from("CT_PARALLEL_DOWNLOADS=y"));
+            opts.push(format!("CT_PARALLEL_DOWNLOADS_COUNT={}", n));
+        }
+        if let Some(level) = &self.ct_log_level {
+            opts.push(format!("CT_LOG_{}=y", level));
+            opts.push(format!("CT_LOG_LEVEL_MAX=\"{}\"", level));
+        }
+        let env_mirror = env::var("CHAINED_MIRROR").ok();
+        let (mirror, fallback) = match (&self.ct_mirror, env_mirror) {
+            (Some(mirror), fallback) => (Some(mirror.clone()), fallback.filter(|f| f != mirror)),
+            (None, mirror) => (mirror, None),
+        };
+        if let Some(mirror) = mirror {
+            opts.push(String::from("CT_USE_MIRROR=y"));
+            opts.push(format!("CT_MIRROR_BASE_URL=\"{}\"", mirror));
+        }
+        if let Some(fallback) = fallback {
+            opts.push(format!("CT_MIRROR_BASE_URL_1=\"{}\"", fallback));
+        }
+        if !self.gdb_enabled {
+            opts.push(String::from("CT_DEBUG_GDB=n"));
+        } else if let Some(version) = &self.gdb_version {
+            opts.push(String::from("CT_DEBUG_GDB=y"));
+            opts.push(format!("CT_GDB_VERSION=\"{}\"", version));
+        }
+        if self.strace_enabled {
+            opts.push(String::from("CT_DEBUG_STRACE=y"));
+            if let Some(version) = &self.strace_version {
+                opts.push(format!("CT_STRACE_VERSION=\"{}\"", version));
+            }
+        }
+        if let Some(downloader) = self.downloader {
+            opts.push(format!("CT_DOWNLOAD_AGENT_{}=y", downloader));
+        }
+        if let Some(wget_opts) = &self.wget_opts {
+            opts.push(format!("CT_DOWNLOAD_WGET_OPTIONS=\"{}\"", wget_opts));
+        }
+        if let Some(curl_opts) = &self.curl_opts {
+            opts.push(format!("CT_DOWNLOAD_CURL_OPTIONS=\"{}\"", curl_opts));
+        }
+        if let Some(build) = &self.build_triple {
+            opts.push(format!("CT_BUILD=\"{}\"", build));
+        }
+        if let Some(host) = &self.host_triple {
+            opts.push(String::from("CT_CANADIAN=y"));
+            opts.push(format!("CT_HOST=\"{}\"", host));
+            if let Some(sysroot) = &self.host_sysroot {
+                opts.push(format!("CT_HOST_SYSROOT=\"{}\"", sysroot.display()));
+                opts.push(format!("CT_HOST_PKG_CONFIG_PATH=\"{}\"", sysroot.join("usr/lib/pkgconfig").display()));
+            }
+        }
+        if let Some(jobs) = self.ct_jobs {
+            opts.push(format!("CT_PARALLEL_JOBS={}", jobs));
+        }
+        if self.save_steps || !self.debug_pause_steps.is_empty() {
+            opts.push(String::from("CT_DEBUG_CT=y"));
+        }
+        if self.save_steps {
+            opts.push(String::from("CT_DEBUG_CT_SAVE_STEPS=y"));
+        }
+        if !self.debug_pause_steps.is_empty() {
+            opts.push(format!("CT_DEBUG_PAUSE_STEPS=\"{}\"", self.debug_pause_steps.join(" ")));
+            opts.push(String::from("CT_DEBUG_PAUSE_STEPS_SHELL=y"));
+        }
+        if self.ct_overlay.is_some() {
+            opts.push(String::from("CT_PATCH_BUNDLED_LOCAL=y"));
+            opts.push(format!("CT_LOCAL_PATCH_DIR=\"{}\"", self.overlay_dir().display()));
+        } else if let Some(dir) = &self.local_patch_dir {
+            opts.push(String::from("CT_PATCH_BUNDLED_LOCAL=y"));
+            opts.push(format!("CT_LOCAL_PATCH_DIR=\"{}\"", dir.display()));
+        }
+        if let Some((kernel, headers)) = self.kernel_versions() {
+            opts.push(format!("CT_LINUX_VERSION=\"{}\"", kernel));
+            opts.push(format!("CT_LINUX_HEADERS_VERSION=\"{}\"", headers));
+        }
+        if let Some(cflags) = self.host_cflags() {
+            opts.push(format!("CT_CFLAGS_FOR_HOST=\"{}\"", cflags));
+        }
+        if self.offline {
+            opts.push(String::from("CT_FORBID_DOWNLOAD=y"));
+        }
+        let mut host_libs: Vec<_> = self.host_lib_versions.iter().collect();
+        host_libs.sort();
+        for (lib, version) in host_libs {
+            opts.push(format!("CT_{}_VERSION=\"{}\"", lib.to_uppercase(), version));
+        }
+        opts.push(format!("CT_THREADS=\"{}\"", self.threads()));
+        if let Some(alias) = &self.target_alias {
+            opts.push(format!("CT_TARGET_ALIAS=\"{}\"", alias));
+        }
+        if self.static_toolchain {
+            opts.push(String::from("CT_STATIC_TOOLCHAIN=y"));
+        }
+        if let Some(prefix) = &self.sysroot_dir_prefix {
+            opts.push(format!("CT_SYSROOT_DIR_PREFIX=\"{}\"", prefix));
+        }
+        if let Some(suffix) = &self.sysroot_suffix {
+            opts.push(format!("CT_SYSROOT_DIR_SUFFIX=\"{}\"", suffix));
+        }
+        if self.allow_root {
+            opts.push(String::from("CT_ALLOW_BUILD_AS_ROOT=y"));
+            opts.push(String::from("CT_ALLOW_BUILD_AS_ROOT_SURE=y"));
+        }
+        if self.graphite_enabled {
+            opts.push(String::from("CT_CC_GCC_GRAPHITE=y"));
+            if let Some(version) = &self.isl_version {
+                opts.push(format!("CT_ISL_VERSION=\"{}\"", version));
+            }
+        }
+        if !self.sanitizers.is_empty() {
+            opts.push(String::from("CT_CC_GCC_LIBSANITIZER=y"));
+        }
+        if self.multilib {
+            opts.push(String::from("CT_MULTILIB=y"));
+        }
+        if self.strip_host {
+            opts.push(String::from("CT_STRIP_HOST_TOOLCHAIN_EXECUTABLES=y"));
+        }
+    }
+    fn crosstool_config_hash(&self, cfg: &Config) -> String {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(self.crosstool_config(cfg))
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+    fn warn_stale_config(&self, cfg: &Config) {
+        if let Some(hash) = &self.config_hash && *hash != self.crosstool_config_hash(cfg) {
+            log::warn!("The configuration of {} changed since its .config was generated, run `toolchain {} rebuild` to regenerate it", self.triple, self.triple);
+        }
+    }
+    // Where sources come from and where the toolchain goes, which chained
+    // always controls, even on top of a sample
+    fn emit_source_config(&self, cfg: &Config, opts: &mut Vec<String>) {
         opts.push(format!("CT_LOCAL_TARBALLS_DIR=\"{}\"", cfg.cache_dir.display()));
         opts.push(format!("CT_PREFIX_DIR=\"{}\"", self.prefix.display()));
 
         opts.push(String::from("CT_GCC_SRC_DEVEL=y"));
         opts.push(format!("CT_GCC_DEVEL_URL=\"{}\"", self.gcc_src));
-
+    }
+    fn emit_gcc_extra_config(&self, opts: &mut Vec<String>) {
+        opts.push(format!("CT_CC_GCC_EXTRA_CONFIG_ARRAY=\"{}\"", kconfig_escape(&self.gcc_extra_config().join(" "))));
+    }
+    fn sample_overrides(&self, cfg: &Config) -> String {
+        let mut opts = Vec::new();
+        self.emit_toolchain_config(cfg, &mut opts);
+        opts.into_iter().map(|v| v + "\n").collect()
+    }
+    fn config_fragments(&self) -> Result<String> {
+        let mut config = String::new();
+        for fragment in self.ct_config_fragments.iter() {
+            let contents = fs::read_to_string(fragment)
+                .with_context(|| format!("Failed to read config fragment {}", fragment.display()))?;
+            config.push_str(&contents);
+            if !config.ends_with('\n') {
+                config.push('\n');
+            }
+        }
+        Ok(config)
+    }
+    fn hardening_cflags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let Some(level) = self.fortify_source {
+            // _FORTIFY_SOURCE does nothing without optimizations
+            flags.push(format!("-D_FORTIFY_SOURCE={level}"));
+            flags.push(String::from("-O2"));
+        }
+        if let Some(protector) = self.stack_protector {
+            flags.push(protector.cflag().to_string());
+        }
+        flags
+    }
+    // User CFLAGS go last, so they can override the hardening ones
+    fn target_cflags(&self) -> Option<String> {
+        let mut flags = self.hardening_cflags();
+        flags.extend(self.target_cflags.clone());
+        flags.extend(self.target_opt_cflags.clone());
+        (!flags.is_empty()).then(|| flags.join(" "))
+    }
+    // Flags for building the toolchain binaries themselves
+    fn host_cflags(&self) -> Option<String> {
+        let mut flags = Vec::new();
+        if self.debug_build {
+            flags.extend(["-g", "-O0"]);
+        } else if self.lto_enabled {
+            flags.push("-O2");
+        }
+        if self.lto_enabled {
+            flags.push("-flto");
+        }
+        (!flags.is_empty()).then(|| flags.join(" "))
+    }
+    fn libc_extra_config(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        match self.libc_locales {
+            Some(true) if self.triple.is_glibc() => flags.push(String::from("--enable-nls")),
+            Some(false) if self.triple.is_glibc() => flags.push(String::from("--disable-nls")),
+            _ => (),
+        }
+        if !self.nptl && self.triple.is_glibc() {
+            flags.push(String::from("--without-nptl"));
+        }
+        flags.extend(self.libc_extra_config.iter().cloned());
+        flags
+    }
+    fn cxx_abi(&self) -> CxxAbi {
+        self.cxx_abi.unwrap_or(CxxAbi::Libstdcxx)
+    }
+    fn threads(&self) -> GccThreads {
+        match self.threads {
+            Some(threads) => threads,
+            None if self.triple.is_linux() => GccThreads::Posix,
+            None => GccThreads::None,
+        }
+    }
+    // Kernel and headers versions, either one standing in for the other
+    fn kernel_versions(&self) -> Option<(&str, &str)> {
+        if !self.triple.is_linux() {
+            return None;
+        }
+        match (self.kernel_version.as_deref(), self.kernel_headers_version.as_deref()) {
+            (Some(kernel), Some(headers)) => Some((kernel, headers)),
+            (Some(v), None) | (None, Some(v)) => Some((v, v)),
+            (None, None) => None,
+        }
+    }
+    fn emit_jit_config(&self, opts: &mut Vec<String>) {
         opts.push(String::from("CT_CC_LANG_JIT=y"));
         opts.push(String::from("CT_EXPERIMENTAL=y"));
-        opts.push(String::from("CT_CC_GCC_EXTRA_CONFIG_ARRAY=\"--enable-host-shared --disable-bootstrap\""));
-
-        opts.into_iter().map(|v| v + "\n").collect()
     }
-    fn env_vars(&self) -> Result<Vec<CString>> {
-        use std::ffi::CString;
+    fn gcc_extra_config(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if self.host_shared {
+            flags.push(String::from("--enable-host-shared"));
+        }
+        flags.push(String::from("--disable-bootstrap"));
+        if self.debug_build {
+            flags.push(String::from("--enable-checking=yes"));
+        }
+        if self.lto_enabled {
+            flags.push(String::from("--enable-lto"));
+        }
+        if !self.sanitizers.is_empty() {
+            flags.push(String::from("--enable-libsanitizer"));
+        }
+        flags.extend(self.gcc_config_extra.iter().cloned());
+        flags
+    }
+    // Word sizes the toolchain has libraries for
+    fn supported_bits(&self) -> Vec<u32> {
+        if self.multilib {
+            return vec![32, 64];
+        }
+        let bits = self.triple.bits();
+        [(Bits::B_32, 32), (Bits::B_64, 64)].into_iter()
+            .filter_map(|(b, n)| bits.contains(b).then_some(n))
+            .collect()
+    }
+    fn warn_flag_conflicts(&self, flags: &str) {
+        let bits = self.supported_bits();
+        for flag in flags.split_whitespace() {
+            let conflicts = match flag {
+                "-m32" => !bits.contains(&32),
+                "-m64" => !bits.contains(&64),
+                _ => false,
+            };
+            if conflicts {
+                log::warn!("Flag {flag} conflicts with the {} architecture", self.triple);
+            }
+        }
+    }
+    // Written by ct-ng itself, since it runs in the base directory
+    fn build_log(&self) -> PathBuf {
+        self.basedir.join("build.log")
+    }
+    // Console output of ct-ng, kept apart from its own build.log
+    fn build_output_log(&self) -> PathBuf {
+        self.basedir.join("build-output.log")
+    }
+    fn build_stats(&self) -> Result<()> {
+        let log_path = self.build_log();
+        let log = fs::read_to_string(&log_path)
+            .with_context(|| format!("Failed to read build log from {}", log_path.display()))?;
 
-        let bin_dir = self.prefix.join("bin");
-        let path = env::var("PATH")
-            .unwrap_or_default();
-        let path = format!("PATH={}:{}", bin_dir.display(), path);
+        let mut steps = ct_log::parse_ct_log_step(&log);
+        if steps.is_empty() {
+            bail!("No build steps found in {}", log_path.display());
+        }
+        steps.sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
 
-        let lib_dir = self.prefix.join("lib");
-        let ld_path = if let Some(ld) = env::var("LD_LIBRARY_PATH").ok() {
-            format!("LD_LIBRARY_PATH={}:{}", lib_dir.display(), ld)
-        } else {
-            format!("LD_LIBRARY_PATH={}", lib_dir.display())
-        };
+        for (name, duration) in steps {
+            let secs = duration.as_secs();
+            println!("{:>3}:{:02}:{:02}  {}", secs / 3600, secs / 60 % 60, secs % 60, name);
+        }
 
-        let qemu_ld_prefix = self.prefix
-            .join(self.triple.to_string())
-            .join("sysroot");
-        let qemu_ld_prefix = format!("QEMU_LD_PREFIX={}", qemu_ld_prefix.display());
+        Ok(())
+    }
+    fn gcc_configure_flags(&self) -> Result<Vec<String>> {
+        let log_path = self.build_log();
+        if !log_path.exists() {
+            bail!("No build log at {}, has the toolchain been compiled?", log_path.display());
+        }
+        let log = fs::read_to_string(&log_path)
+            .with_context(|| format!("Failed to read build log from {}", log_path.display()))?;
 
-        let triple_for_env = self.triple.to_string().replace('-', "_").to_uppercase();
-        let set_linker = format!("CARGO_TARGET_{}_LINKER={}-gcc", triple_for_env, self.triple.to_string());
+        // The last GCC configure invocation is the final compiler
+        let args = log.lines()
+            .map(split_shell_words)
+            .rfind(|words| words.iter().any(|w| is_gcc_configure(w)))
+            .context("No GCC configure invocation found in the build log")?;
 
-        Ok(vec![
-            CString::new(path)?,
-            CString::new(ld_path)?,
-            CString::new(qemu_ld_prefix)?,
-            CString::new(set_linker)?,
-        ])
+        Ok(args.into_iter()
+            .skip_while(|w| !is_gcc_configure(w))
+            .skip(1)
+            .filter(|w| w.starts_with("--"))
+            .collect())
     }
-    fn shell(&self) -> Result<()> {
-        use std::ffi::CString;
-        let mut env = self.env_vars()?;
+    fn json_spec(&self, pre_link_args: &[String], post_link_args: &[String], link_env: &[String]) -> serde_json::Value {
+        let mut spec = serde_json::Map::new();
+        self.triple.emit_json_spec(&mut spec);
 
-        let shell: CString = CString::new(env::var("SHELL")
-            .unwrap())?;
+        let mut pre = self.triple.default_pre_link_args();
+        pre.extend(pre_link_args.iter().cloned());
 
-        let prompt = CString::new(
-            format!(r#"PROMPT_COMMAND=if [ "$SET_PS1" != "true" ]; then SET_PS1=true; PS1="[{}] $PS1"; fi "#, self.triple),
-        )?;
+        spec.insert("linker".into(), self.tool_name("gcc").into());
+        spec.insert("linker-flavor".into(), "gcc".into());
+        spec.insert("pre-link-args".into(), serde_json::json!({ "gcc": pre }));
+        spec.insert("post-link-args".into(), serde_json::json!({ "gcc": post_link_args }));
+        spec.insert("link-env".into(), link_env.into());
 
-        env.push(prompt);
+        spec.into()
+    }
+    fn write_json_spec(&self, pre_link_args: &[String], post_link_args: &[String], link_env: &[String]) -> Result<()> {
+        if let Some(bad) = link_env.iter().find(|v| !v.contains('=')) {
+            bail!("Invalid link environment variable {bad}, expected KEY=VALUE");
+        }
 
-        nix::unistd::execvpe(&shell, &[&shell], &env)
-            .context("Failed to exec into shell")?;
+        let spec = self.json_spec(pre_link_args, post_link_args, link_env);
+        let spec = serde_json::to_string_pretty(&spec)
+            .context("Failed to serialize JSON target specification")?;
 
-        Ok(())
+        self.create_basedir()?;
+        fs::write(&self.json_spec, spec)
+            .with_context(|| format!("Failed to write JSON target specification to {}", self.json_spec.display()))?;
 
+        Ok(())
     }
-    fn nconfig(&self) -> Result<()> {
-        let status = Command::new("ct-ng")
-            .arg("nconfig")
-            .current_dir(&self.basedir)
-            .status()
-            .context("Failed to set crosstool config")?;
-        if !status.success() {
-            if let Some(c) = status.code() {
-                bail!("ct-ng nconfig exited with a non-zero status code {c}")
-            } else {
-                bail!("ct-ng nconfig died")
-            }
+    fn tool_name(&self, tool: &str) -> String {
+        match &self.target_alias {
+            Some(alias) => format!("{}-{}", alias, tool),
+            None => format!("{}-{}", self.triple.to_gnu_triplet(), tool),
         }
-
-        Ok(())
     }
-    fn defconfig(&self, cfg: &Config) -> Result<()> {
-        let ct_cfg = self.crosstool_config(&cfg);
-
-        if !self.basedir.exists() {
-            fs::create_dir(&self.basedir)
-                .context("Failed to create new target's base directory")?;
+    fn tool_binary(&self, tool: &str) -> PathBuf {
+        self.prefix.join("bin").join(self.tool_name(tool))
+    }
+    fn gcc_binary(&self) -> PathBuf {
+        self.tool_binary("gcc")
+    }
+    fn resolve_tool(&self, name: &str) -> Option<PathBuf> {
+        let path = if name.contains('-') {
+            self.prefix.join("bin").join(name)
+        } else {
+            self.tool_binary(name)
+        };
+        path.exists().then_some(path)
+    }
+    fn is_installed(&self) -> bool {
+        self.gcc_binary().exists()
+    }
+    fn status(&self) -> &'static str {
+        if self.is_installed() { "installed" } else { "not built" }
+    }
+    fn sysroot_path(&self) -> PathBuf {
+        self.prefix
+            .join(self.triple.to_gnu_triplet())
+            .join("sysroot")
+    }
+    fn derived_paths(&self) -> Vec<(&'static str, PathBuf)> {
+        let mut paths = vec![
+            ("gcc_binary", self.gcc_binary()),
+            ("cxx_binary", self.tool_binary("g++")),
+            ("ar_binary", self.tool_binary("ar")),
+            ("objcopy_binary", self.tool_binary("objcopy")),
+            ("sysroot_path", self.sysroot_path()),
+        ];
+        if self.strace_enabled {
+            paths.push(("strace_binary", self.strace_path()));
         }
+        paths
+    }
+    fn strace_path(&self) -> PathBuf {
+        self.sysroot_path().join("usr/bin/strace")
+    }
+    fn env_map(&self) -> Result<HashMap<String, String>> {
+        let mut vars = HashMap::new();
 
-        let defconfig_path = self.basedir.join("defconfig");
-        log::debug!("Defconfig is at {}", defconfig_path.display());
+        let bin_dir = self.prefix.join("bin");
+        let path = env::var("PATH")
+            .unwrap_or_default();
+        vars.insert("PATH".into(), format!("{}:{}", bin_dir.display(), path));
 
-        fs::write(&defconfig_path, &ct_cfg)
-            .context("Failed to write defconfig file")?;
+        let lib_dir = self.prefix.join("lib");
+        let ld_path = if let Ok(ld) = env::var("LD_LIBRARY_PATH") {
+            format!("{}:{}", lib_dir.display(), ld)
+        } else {
+            lib_dir.display().to_string()
+        };
+        vars.insert("LD_LIBRARY_PATH".into(), ld_path);
+
+        vars.insert("QEMU_LD_PREFIX".into(), self.sysroot_path().display().to_string());
+
+        vars.insert(self.cargo_linker_var(), self.tool_name("gcc"));
 
-        log::debug!("Running ct-ng defconfig");
-        let status = Command::new("ct-ng")
-            .arg("defconfig")
-            .current_dir(&self.basedir)
+        vars.extend(self.custom_env.iter().cloned());
+
+        Ok(vars)
+    }
+    fn env_vars(&self) -> Result<Vec<CString>> {
+        let mut vars = self.env_map()?;
+        for key in PASSTHROUGH_ENV {
+            if let Ok(value) = env::var(key) {
+                vars.entry(key.to_string()).or_insert(value);
+            }
+        }
+
+        vars.into_iter()
+            .map(|(k, v)| Ok(CString::new(format!("{k}={v}"))?))
+            .collect()
+    }
+    fn cargo_linker_var(&self) -> String {
+        let triple_for_env = self.triple.to_string().replace('-', "_").to_uppercase();
+        format!("CARGO_TARGET_{}_LINKER", triple_for_env)
+    }
+    fn run(&self, cmd: &[String]) -> Result<()> {
+        let (program, args) = cmd.split_first()
+            .context("No command given")?;
+        let status = Command::new(program)
+            .args(args)
+            .envs(self.env_map()?)
             .status()
-            .context("Failed to set crosstool config")?;
+            .with_context(|| format!("Failed to run {program}"))?;
         if !status.success() {
             if let Some(c) = status.code() {
-                bail!("ct-ng defconfig exited with a non-zero status code {c}")
+                bail!("{program} exited with a non-zero status code {c}")
             } else {
-                bail!("ct-ng defconfig died")
+                bail!("{program} died")
             }
         }
 
         Ok(())
     }
-    fn compile(&self) -> Result<()> {
-        log::info!("Compiling...");
-        let status = Command::new("ct-ng")
-            .arg("build")
-            .current_dir(&self.basedir)
-            .status()
-            .context("Failed to build toolchain")?;
-        if !status.success() {
-            if let Some(c) = status.code() {
-                bail!("ct-ng build exited with a non-zero status code {c}")
+    fn inspect(&self, tool: Option<InspectTool>) -> Result<()> {
+        let tool = tool.unwrap_or_else(|| {
+            if find_in_path("tree").is_some() {
+                InspectTool::Tree
             } else {
-                bail!("ct-ng build died")
+                InspectTool::Ls
             }
+        });
+        let (program, args): (&str, &[&str]) = match tool {
+            InspectTool::Tree => ("tree", &[]),
+            InspectTool::Ls => ("ls", &["-la"]),
+            InspectTool::Xdg => ("xdg-open", &[]),
+        };
+        if find_in_path(program).is_none() {
+            bail!("{program} is not installed");
+        }
+
+        let status = Command::new(program)
+            .args(args)
+            .arg(&self.prefix)
+            .status()
+            .with_context(|| format!("Failed to run {program}"))?;
+        if !status.success() {
+            bail!("{program} failed");
         }
+
         Ok(())
     }
-}
+    // Lays out a Yocto-style SDK: the prefix under sysroots/, plus an
+    // environment-setup-<triple> script and a version-<triple> file
+    fn pack(&self, output: &Path, relocatable: bool) -> Result<()> {
+        if !self.is_installed() {
+            bail!("Toolchain {} is not installed yet", self.triple);
+        }
+        let output = env::current_dir()?.join(output);
 
+        let tmp = env::temp_dir().join(format!("chained-pack-{}", std::process::id()));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        let res = self.write_sdk(&tmp, relocatable).and_then(|()| {
+            let status = Command::new("tar")
+                .arg("-caf")
+                .arg(&output)
+                .arg("-C")
+                .arg(&tmp)
+                .arg(".")
+                .status()
+                .context("Failed to run tar")?;
+            if !status.success() {
+                bail!("tar failed to create {}", output.display());
+            }
+            Ok(())
+        });
+        let _ = fs::remove_dir_all(&tmp);
+        res
+    }
+    fn write_sdk(&self, dir: &Path, relocatable: bool) -> Result<()> {
+        let triple = self.triple.to_string();
+        copy_dir_all(&self.prefix, &dir.join("sysroots").join(&triple))
+            .context("Failed to copy the toolchain")?;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    cache_dir: PathBuf,
-    data_dir: PathBuf,
-    toolchain: Vec<Toolchain>,
-}
+        // Without relocation the SDK's toolchain has to end up where this one lives now
+        let sysroot = if relocatable {
+            format!("$SDK_DIR/sysroots/{triple}")
+        } else {
+            self.prefix.display().to_string()
+        };
+        fs::write(dir.join(format!("environment-setup-{triple}")), self.sdk_env_script(&sysroot, relocatable)?)?;
 
-impl Config {
-    fn save(&self) -> Result<()> {
-        let to_save = toml::to_string(&self)
-            .context("Failed to serialize config")?;
-        fs::write(Self::path(), &to_save)
-            .context("Failed to save the config")?;
+        let gcc_version = Command::new(self.gcc_binary())
+            .arg("-dumpversion")
+            .output()
+            .ok()
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+            .unwrap_or_default();
+        fs::write(dir.join(format!("version-{triple}")), format!(
+            "Target: {triple}\nGCC: {gcc_version}\nGCC source: {}\nPacked by: chained {}\n",
+            self.gcc_src,
+            env!("CARGO_PKG_VERSION"),
+        ))?;
         Ok(())
     }
-    fn path() -> PathBuf {
-        let dirs = directories::ProjectDirs::from("", "", "chained").unwrap();
-        dirs.config_local_dir()
-            .join("chained.toml")
-    }
-    fn load() -> Result<(Config, PathBuf)> {
-        let path = Self::path();
-        let cfg_string = fs::read_to_string(&path)
-            .with_context(|| format!("Failed to open and read config file from {}", path.display()))?;
+    // Shell script exporting env_map(), with the prefix replaced by `sysroot`,
+    // which may refer to $SDK_DIR, the directory the script is in
+    fn sdk_env_script(&self, sysroot: &str, relocatable: bool) -> Result<String> {
+        let prefix = self.prefix.display().to_string();
+        let quote = |v: &str| v.replace('\\', r"\\")
+            .replace('"', "\\\"")
+            .replace('$', r"\$")
+            .replace('`', r"\`")
+            .replace(&prefix, sysroot);
 
-        let me = toml::from_str(&cfg_string)
+        let mut script = format!("# Environment setup for the {} SDK\n", self.triple);
+        if relocatable {
+            script.push_str("SDK_DIR=\"$(cd \"$(dirname \"${BASH_SOURCE:-$0}\")\" && pwd)\"\n");
+        }
+        let mut vars: Vec<_> = self.env_map()?.into_iter().collect();
+        vars.sort();
+        for (k, v) in vars {
+            let line = match k.as_str() {
+                "PATH" => format!("export PATH=\"{sysroot}/bin:$PATH\""),
+                "LD_LIBRARY_PATH" => format!("export LD_LIBRARY_PATH=\"{sysroot}/lib${{LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}}\""),
+                _ => format!("export {k}=\"{}\"", quote(&v)),
+            };
+            script.push_str(&line);
+            script.push('\n');
+        }
+        Ok(script)
+    }
+    fn cargo_config(&self) -> Result<String> {
+        let mut vars = self.env_map()?;
+        vars.remove("PATH");
+        vars.remove(&self.cargo_linker_var());
+
+        let linker = self.gcc_binary();
+        let mut target = toml::Table::new();
+        target.insert("linker".into(), linker.display().to_string().into());
+
+        let mut targets = toml::Table::new();
+        targets.insert(self.triple.to_string(), target.into());
+
+        let mut cargo_cfg = toml::Table::new();
+        cargo_cfg.insert("target".into(), targets.into());
+        cargo_cfg.insert("env".into(), vars.into_iter()
+            .map(|(k, v)| (k, v.into()))
+            .collect::<toml::Table>()
+            .into());
+
+        toml::to_string(&cargo_cfg)
+            .context("Failed to serialize cargo config")
+    }
+    fn shell(&self, prompt: Option<&str>) -> Result<()> {
+        use std::ffi::CString;
+        let mut env = self.env_vars()?;
+
+        let shell_path = env::var("SHELL")
+            .context("SHELL is not set")?;
+        let shell = CString::new(shell_path.as_str())?;
+
+        let mut argv = vec![shell.clone()];
+        if let Some(prompt) = prompt {
+            let prompt = prompt.replace("{triple}", &self.triple.to_string());
+            let (var, args) = ShellKind::detect(&shell_path).prompt_setup(&prompt);
+            if let Some(var) = var {
+                env.push(CString::new(var)?);
+            }
+            for arg in args {
+                argv.push(CString::new(arg)?);
+            }
+        }
+
+        nix::unistd::execvpe(&shell, &argv, &env)
+            .context("Failed to exec into shell")?;
+
+        Ok(())
+
+    }
+    fn ct_ng_command(&self, target: &str) -> Command {
+        log::debug!("Running ct-ng {target}");
+        let mut cmd = Command::new("ct-ng");
+        cmd.arg(target).current_dir(&self.basedir);
+        if self.allow_root {
+            cmd.env("CT_ALLOW_BUILD_AS_ROOT", "y").env("CT_ALLOW_BUILD_AS_ROOT_SURE", "y");
+        }
+        cmd.envs(CT_NG_EXTRA_ENV.get().into_iter().flatten().map(|(k, v)| (k, v)));
+        cmd
+    }
+    fn ct_ng(&self, target: &str) -> Result<()> {
+        let status = self.ct_ng_command(target)
+            .status()
+            .with_context(|| format!("Failed to run ct-ng {target}"))?;
+        check_ct_ng_status(target, status)
+    }
+    // Runs a ct-ng build in its own process group, so the whole build can be
+    // torn down on Ctrl+C or SIGTERM instead of being left running in the
+    // background. When nobody is watching the terminal, the output also gets
+    // teed into build-output.log and a timestamped copy of it. It's colored
+    // when somebody is, unless `color` forces it either way. Pausing at steps
+    // leaves the output and the process group alone, since ct-ng's prompt and
+    // pause shell don't end their lines and have to read from the terminal
+    fn ct_ng_build(&self, target: &str, color: Option<bool>) -> Result<()> {
+        let pausing = !self.debug_pause_steps.is_empty();
+        let capture = !pausing && !std::io::stdout().is_terminal();
+        let color = !pausing && color.unwrap_or(!capture);
+        let logs = if capture {
+            self.create_build_logs()?
+        } else {
+            Vec::new()
+        };
+
+        let mut cmd = self.ct_ng_command(target);
+        if !pausing {
+            cmd.process_group(0);
+        }
+        if capture || color {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        install_cancel_handler()?;
+        BUILD_IN_FOREGROUND.store(pausing, Ordering::SeqCst);
+        let mut child = cmd.spawn()
+            .with_context(|| format!("Failed to run ct-ng {target}"))?;
+        if !pausing {
+            BUILD_PGID.store(child.id() as i32, Ordering::SeqCst);
+        }
+
+        for reader in tee_output(&mut child, logs, color) {
+            let _ = reader.join();
+        }
+        let status = child.wait()
+            .with_context(|| format!("Failed to wait for ct-ng {target}"));
+        BUILD_PGID.store(0, Ordering::SeqCst);
+        BUILD_IN_FOREGROUND.store(false, Ordering::SeqCst);
+        if capture {
+            self.rotate_build_logs(5);
+        }
+
+        if BUILD_CANCELLED.load(Ordering::SeqCst) {
+            return Err(ChainedError::BuildCancelled.into());
+        }
+        check_ct_ng_status(target, status?)
+    }
+    fn create_build_logs(&self) -> Result<Vec<File>> {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let history = self.basedir.join(format!("build-{stamp}.log"));
+        [self.build_output_log(), history].iter()
+            .map(|path| File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display())))
+            .collect()
+    }
+    fn rotate_build_logs(&self, keep: usize) {
+        let Ok(entries) = fs::read_dir(&self.basedir) else {
+            return;
+        };
+        let mut old: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(is_history_log))
+            .collect();
+        old.sort();
+        let excess = old.len().saturating_sub(keep);
+        for path in &old[..excess] {
+            if let Err(e) = fs::remove_file(path) {
+                log::warn!("Failed to remove old log {}: {e}", path.display());
+            }
+        }
+    }
+    fn create_basedir(&self) -> Result<()> {
+        if !self.basedir.exists() {
+            fs::create_dir(&self.basedir)
+                .context("Failed to create new target's base directory")?;
+        }
+        Ok(())
+    }
+    fn overlay_dir(&self) -> PathBuf {
+        self.basedir.join("overlays")
+    }
+    fn install_overlay(&self) -> Result<()> {
+        let Some(overlay) = &self.ct_overlay else {
+            return Ok(());
+        };
+        let dst = self.overlay_dir();
+        if dst.exists() {
+            fs::remove_dir_all(&dst)
+                .with_context(|| format!("Failed to remove old {}", dst.display()))?;
+        }
+        copy_dir_all(overlay, &dst)
+    }
+    // The overlay is copied at add time, so edits to the original won't be
+    // picked up without adding the toolchain again
+    fn check_overlay(&self) {
+        let Some(overlay) = &self.ct_overlay else {
+            return;
+        };
+        if !overlay.is_dir() {
+            log::warn!("Overlay directory {} doesn't exist anymore, building with the copy from when it was added", overlay.display());
+            return;
+        }
+        match (dir_contents(overlay), dir_contents(&self.overlay_dir())) {
+            (Ok(orig), Ok(copy)) if orig != copy => {
+                log::warn!("Overlay directory {} changed since the toolchain was added, building with the old copy", overlay.display());
+            },
+            (Err(e), _) | (_, Err(e)) => log::warn!("Failed to compare overlay directories: {e}"),
+            _ => (),
+        }
+    }
+    fn check_local_patch_dir(&self) -> Result<()> {
+        if let Some(dir) = &self.local_patch_dir && !dir.is_dir() {
+            bail!("Local patch directory {} doesn't exist", dir.display());
+        }
+        Ok(())
+    }
+    fn nconfig(&self) -> Result<()> {
+        self.ct_ng("nconfig")
+            .context("Failed to set crosstool config")
+    }
+    fn defconfig(&self, cfg: &Config) -> Result<()> {
+        self.check_local_patch_dir()?;
+        let mut ct_cfg = self.crosstool_config(cfg);
+        ct_cfg.push_str(&self.config_fragments()?);
+
+        self.create_basedir()?;
+
+        let defconfig_path = self.basedir.join("defconfig");
+        log::debug!("Defconfig is at {}", defconfig_path.display());
+
+        fs::write(&defconfig_path, &ct_cfg)
+            .context("Failed to write defconfig file")?;
+
+        self.ct_ng("defconfig")
+            .context("Failed to set crosstool config")
+    }
+    // Starts from the sample's defaults, then puts chained's own settings on
+    // top, the later assignments win when ct-ng reads the config again
+    fn load_sample(&self, sample: &str, cfg: &Config) -> Result<()> {
+        self.create_basedir()?;
+
+        self.ct_ng(&format!("show-{sample}"))
+            .with_context(|| format!("Failed to show ct-ng sample {sample}"))?;
+        self.ct_ng(sample)
+            .with_context(|| format!("Failed to load ct-ng sample {sample}"))?;
+
+        let config = self.apply_sample_overrides(self.read_ct_config()?, cfg)?;
+        fs::write(self.basedir.join(".config"), config)
+            .context("Failed to apply chained's settings to the sample")?;
+        self.ct_ng("olddefconfig")
+            .context("Failed to update the sample config")
+    }
+    fn apply_sample_overrides(&self, mut config: String, cfg: &Config) -> Result<String> {
+        if !config.ends_with('\n') {
+            config.push('\n');
+        }
+        config.push_str(&self.sample_overrides(cfg));
+        config.push_str(&self.config_fragments()?);
+        Ok(config)
+    }
+    fn read_ct_config(&self) -> Result<String> {
+        let config_path = self.basedir.join(".config");
+        fs::read_to_string(&config_path)
+            .with_context(|| format!("Failed to read {}", config_path.display()))
+    }
+    // Name prefixes of the tarballs ct-ng looks for in the cache directory
+    fn expected_tarballs(&self) -> Vec<String> {
+        let mut names = vec!["gcc", "binutils", "gmp", "mpfr", "mpc"];
+        if self.triple.is_linux() {
+            names.push("linux");
+        }
+        names.extend(self.triple.libc_name());
+        names.into_iter().map(|n| format!("{n}-")).collect()
+    }
+    // Offline builds would only fail once ct-ng gets to the missing source,
+    // which may be a while into the build
+    fn check_offline_sources(&self) -> Result<()> {
+        let config = self.read_ct_config()?;
+        let Some(Some(dir)) = parse_ct_config(&config).get("CT_LOCAL_TARBALLS_DIR").copied() else {
+            bail!("Offline builds need CT_LOCAL_TARBALLS_DIR to be set");
+        };
+        let dir = Path::new(dir.trim_matches('"'));
+        let files: Vec<String> = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read the cache directory {}", dir.display()))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+
+        let missing: Vec<String> = self.expected_tarballs()
+            .into_iter()
+            .filter(|name| !files.iter().any(|f| f.starts_with(name)))
+            .collect();
+        if !missing.is_empty() {
+            let missing: Vec<String> = missing.iter().map(|m| format!("{m}*")).collect();
+            bail!("Missing sources in {} for an offline build: {}, run `download` first", dir.display(), missing.join(", "));
+        }
+        Ok(())
+    }
+    // Downloads all sources, even for offline toolchains
+    fn download(&self) -> Result<()> {
+        if self.offline {
+            self.set_config_option("CT_FORBID_DOWNLOAD", None)?;
+        }
+        let res = self.ct_ng("source");
+        if self.offline {
+            self.set_config_option("CT_FORBID_DOWNLOAD", Some("y"))?;
+        }
+        res.context("Failed to download sources")
+    }
+    fn set_config_option(&self, key: &str, value: Option<&str>) -> Result<()> {
+        let config_path = self.basedir.join(".config");
+        let config = fs::read_to_string(&config_path)
+            .with_context(|| format!("Failed to read {}", config_path.display()))?;
+
+        let new_line = match value {
+            Some(v) => format!("{key}={v}"),
+            None => format!("# {key} is not set"),
+        };
+        let set_prefix = format!("{key}=");
+        let unset_line = format!("# {key} is not set");
+
+        let mut found = false;
+        let mut lines: Vec<String> = config.lines()
+            .map(|l| if l.starts_with(&set_prefix) || l == unset_line {
+                found = true;
+                new_line.clone()
+            } else {
+                l.to_string()
+            })
+            .collect();
+        if !found && value.is_some() {
+            lines.push(new_line);
+        }
+
+        fs::write(&config_path, lines.join("\n") + "\n")
+            .with_context(|| format!("Failed to write {}", config_path.display()))
+    }
+    fn set_ct_log_level(&self, level: CtNgLogLevel) -> Result<()> {
+        use strum::VariantArray;
+        for l in CtNgLogLevel::VARIANTS {
+            let value = (*l == level).then_some("y");
+            self.set_config_option(&format!("CT_LOG_{}", l), value)?;
+        }
+        self.set_config_option("CT_LOG_LEVEL_MAX", Some(&format!("\"{}\"", level)))
+    }
+    // Goes through load_sample, so chained's prefix and sources survive
+    fn update_sample(&self, cfg: &Config) -> Result<()> {
+        let old = self.read_ct_config()
+            .unwrap_or_default();
+
+        let triplet = self.triple.to_gnu_triplet();
+        self.load_sample(self.from_sample.as_deref().unwrap_or(&triplet), cfg)?;
+
+        let new = self.read_ct_config()?;
+        print_line_diff(&old, &new);
+
+        Ok(())
+    }
+    fn compile(&self, jobs: Option<usize>, color: Option<bool>) -> Result<()> {
+        if !self.debug_pause_steps.is_empty() && !std::io::stdin().is_terminal() {
+            log::warn!("The build pauses at {}, but stdin isn't a terminal", self.debug_pause_steps.join(", "));
+        }
+        self.check_overlay();
+        self.check_local_patch_dir()?;
+        if self.offline {
+            self.check_offline_sources()?;
+        }
+        let jobs = jobs.unwrap_or_else(|| {
+            detect_jobs(env::var("CT_JOBS").ok().as_deref(), std::thread::available_parallelism)
+        });
+        log::info!("Compiling with {jobs} jobs...");
+        let target = format!("build.{}", jobs);
+        let res = self.ct_ng_build(&target, color);
+        if let Err(e) = res {
+            if e.is::<ChainedError>() {
+                return Err(e);
+            }
+            self.explain_build_failure();
+            return Err(e).context("Failed to build toolchain");
+        }
+
+        if self.strace_enabled && !self.strace_path().exists() {
+            bail!("strace was enabled, but {} wasn't built", self.strace_path().display());
+        }
+        Ok(())
+    }
+    fn list_steps(&self) -> Result<Vec<String>> {
+        let output = self.ct_ng_command("list-steps")
+            .output()
+            .context("Failed to run ct-ng list-steps")?;
+        check_ct_ng_status("list-steps", output.status)?;
+        Ok(parse_list_steps(&String::from_utf8_lossy(&output.stdout)))
+    }
+    fn compile_step(&self, step: &str, color: Option<bool>) -> Result<()> {
+        let steps = self.list_steps()?;
+        if !steps.iter().any(|s| s == step) {
+            bail!("Unknown build step {step}, available steps: {}", steps.join(", "));
+        }
+        if !self.save_steps {
+            log::warn!("{} wasn't added with --step-by-step, the step likely has no saved state to start from", self.triple);
+        }
+        self.ct_ng_build(step, color)
+    }
+    fn explain_build_failure(&self) {
+        let Ok(log) = fs::read_to_string(self.build_log()) else {
+            return;
+        };
+        let lines: Vec<&str> = log.lines().collect();
+        let tail = lines[lines.len().saturating_sub(100)..].join("\n");
+
+        if let Some(hint) = ct_log::failure_hint(&tail) {
+            let border = "-".repeat(hint.len() + 4);
+            eprintln!("\x1b[33m{border}\n| {hint} |\n{border}\x1b[0m");
+        } else {
+            eprintln!("Last lines of {}:", self.build_log().display());
+            for line in &lines[lines.len().saturating_sub(20)..] {
+                eprintln!("{line}");
+            }
+        }
+    }
+}
+
+// Step names out of `ct-ng list-steps`, which lists them as `  - <step>`
+fn parse_list_steps(output: &str) -> Vec<String> {
+    output.lines()
+        .filter_map(|line| line.trim_start().strip_prefix("- "))
+        .map(|step| step.trim().to_string())
+        .collect()
+}
+
+fn copy_dir_all(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)
+        .with_context(|| format!("Failed to create {}", dst.display()))?;
+    for entry in fs::read_dir(src).with_context(|| format!("Failed to read {}", src.display()))? {
+        let entry = entry?;
+        let to = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &to)
+                .with_context(|| format!("Failed to copy {}", entry.path().display()))?;
+        } else if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &to)?;
+        } else {
+            fs::copy(entry.path(), &to)
+                .with_context(|| format!("Failed to copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+// ct-ng leaves the installed prefix read-only (CT_PREFIX_DIR_RO), so the
+// directories need their write bit back before anything can be removed
+fn remove_dir_all_forced(dir: &Path) -> Result<()> {
+    fn make_writable(dir: &Path) -> std::io::Result<()> {
+        let mut perms = fs::metadata(dir)?.permissions();
+        perms.set_mode(perms.mode() | 0o700);
+        fs::set_permissions(dir, perms)?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                make_writable(&entry.path())?;
+            }
+        }
+        Ok(())
+    }
+    make_writable(dir)
+        .with_context(|| format!("Failed to make {} writable", dir.display()))?;
+    fs::remove_dir_all(dir)
+        .with_context(|| format!("Failed to remove {}", dir.display()))
+}
+
+// Every file under dir with its contents, keyed by path relative to dir
+fn dir_contents(dir: &Path) -> std::io::Result<Vec<(PathBuf, Vec<u8>)>> {
+    fn walk(root: &Path, dir: &Path, out: &mut Vec<(PathBuf, Vec<u8>)>) -> std::io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                walk(root, &path, out)?;
+            } else {
+                let rel = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+                out.push((rel, fs::read(&path)?));
+            }
+        }
+        Ok(())
+    }
+    let mut out = Vec::new();
+    walk(dir, dir, &mut out)?;
+    out.sort();
+    Ok(out)
+}
+
+// Escapes a value for use inside a double-quoted kconfig string
+fn kconfig_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn local_source_path(src: &str) -> Option<&str> {
+    if let Some(path) = src.strip_prefix("file://") {
+        Some(path)
+    } else if src.starts_with('/') {
+        Some(src)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ChainedError {
+    #[error("Build cancelled")]
+    BuildCancelled,
+}
+
+// Set once from `--ct-ng-args`, passed to every ct-ng invocation
+static CT_NG_EXTRA_ENV: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+fn parse_env_assignment(s: &str) -> Result<(String, String)> {
+    let Some((key, value)) = s.split_once('=') else {
+        bail!("expected KEY=VALUE, got `{s}`");
+    };
+    let valid_key = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_key {
+        bail!("`{key}` is not a valid environment variable name");
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+// Host libraries ct-ng builds itself whose version can be pinned
+const HOST_LIBS: &[&str] = &["expat", "ncurses", "zlib", "gettext", "libiconv"];
+
+fn parse_host_lib_version(s: &str) -> Result<(String, String)> {
+    let Some((lib, version)) = s.split_once('=') else {
+        bail!("expected LIB=VERSION, got `{s}`");
+    };
+    if !HOST_LIBS.contains(&lib) {
+        bail!("unknown host library `{lib}`, expected one of: {}", HOST_LIBS.join(", "));
+    }
+    if version.is_empty() {
+        bail!("missing version for `{lib}`");
+    }
+    Ok((lib.to_string(), version.to_string()))
+}
+
+// Process group of the running ct-ng build, or 0 if there is none
+static BUILD_PGID: AtomicI32 = AtomicI32::new(0);
+static BUILD_CANCELLED: AtomicBool = AtomicBool::new(false);
+// A build that shares the terminal's process group gets Ctrl+C by itself
+static BUILD_IN_FOREGROUND: AtomicBool = AtomicBool::new(false);
+
+fn install_cancel_handler() -> Result<()> {
+    static INSTALLED: OnceLock<()> = OnceLock::new();
+    if INSTALLED.get().is_some() {
+        return Ok(());
+    }
+    ctrlc::set_handler(|| {
+        if BUILD_IN_FOREGROUND.load(Ordering::SeqCst) {
+            return;
+        }
+        let pgid = BUILD_PGID.load(Ordering::SeqCst);
+        if pgid == 0 {
+            std::process::exit(130);
+        }
+        BUILD_CANCELLED.store(true, Ordering::SeqCst);
+        let pgid = Pid::from_raw(pgid);
+        let _ = killpg(pgid, Signal::SIGTERM);
+        // Give ct-ng a moment to clean up before pulling the plug
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(5));
+            let _ = killpg(pgid, Signal::SIGKILL);
+        });
+    }).context("Failed to install the signal handler")?;
+    let _ = INSTALLED.set(());
+    Ok(())
+}
+
+// Copies the child's output to stdout and every log, and returns nothing to
+// wait for when neither stdout nor stderr was piped
+fn tee_output(child: &mut Child, logs: Vec<File>, color: bool) -> Vec<JoinHandle<()>> {
+    let logs = Arc::new(Mutex::new(logs));
+    let stdout = child.stdout.take().map(|s| Box::new(s) as Box<dyn Read + Send>);
+    let stderr = child.stderr.take().map(|s| Box::new(s) as Box<dyn Read + Send>);
+    [stdout, stderr].into_iter()
+        .flatten()
+        .map(|pipe| {
+            let logs = Arc::clone(&logs);
+            std::thread::spawn(move || {
+                for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                    if color {
+                        println!("{}", colorize_build_line(&line));
+                    } else {
+                        println!("{line}");
+                    }
+                    for log in logs.lock().unwrap().iter_mut() {
+                        let _ = writeln!(log, "{line}");
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+fn colorize_build_line(line: &str) -> String {
+    let color = if line.contains("ERROR") || line.contains("error:") {
+        "31"
+    } else if line.contains("WARN") || line.contains("warning:") {
+        "33"
+    } else if line.contains("[INFO ]") {
+        "32"
+    } else {
+        return line.to_string();
+    };
+    format!("\x1b[{color}m{line}\x1b[0m")
+}
+
+fn check_ct_ng_status(target: &str, status: ExitStatus) -> Result<()> {
+    if !status.success() {
+        if let Some(c) = status.code() {
+            bail!("ct-ng {target} exited with a non-zero status code {c}")
+        } else if let Some(sig) = status.signal() {
+            match nix::sys::signal::Signal::try_from(sig) {
+                Ok(name) => bail!("ct-ng {target} killed by signal {sig} ({})", name.as_str()),
+                Err(_) => bail!("ct-ng {target} killed by signal {sig}"),
+            }
+        } else {
+            bail!("ct-ng {target} died")
+        }
+    }
+    Ok(())
+}
+
+// Left by setup in the data and cache directories it created itself
+const CREATED_MARKER: &str = ".created-by-chained";
+
+// Directories overridden during setup are only removed whole if chained
+// created them, otherwise just the toolchains in them go
+fn removable_dirs(defaults: Vec<PathBuf>, cfg: Option<&Config>) -> Vec<PathBuf> {
+    let mut dirs = defaults;
+    let Some(cfg) = cfg else {
+        return dirs;
+    };
+    for d in [&cfg.cache_dir, &cfg.data_dir] {
+        if dirs.contains(d) {
+            continue;
+        }
+        if d.join(CREATED_MARKER).exists() {
+            dirs.push(d.clone());
+        } else if d.exists() {
+            log::warn!("Not removing {}, chained didn't create it", d.display());
+        }
+    }
+    for t in cfg.iter_toolchains() {
+        for d in [&t.basedir, &t.prefix] {
+            if !dirs.iter().any(|r| d.starts_with(r)) {
+                dirs.push(d.clone());
+            }
+        }
+    }
+    dirs
+}
+
+// Matches the `build-<unix timestamp>.log` files written by ct_ng_build
+fn is_history_log(name: &str) -> bool {
+    name.strip_prefix("build-")
+        .and_then(|n| n.strip_suffix(".log"))
+        .is_some_and(|stamp| !stamp.is_empty() && stamp.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn check_setup() -> Result<()> {
+    let mut failed = false;
+    let mut report = |what: String, res: Result<()>| {
+        match res {
+            Ok(()) => println!("[ OK ] {what}"),
+            Err(e) => {
+                println!("[FAIL] {what}: {e}");
+                failed = true;
+            },
+        }
+    };
+
+    let cfg = Config::load();
+    report(format!("config file {}", Config::path().display()), cfg.as_ref().map(|_| ()).map_err(|e| anyhow!("{e:#}")));
+    if let Ok((cfg, _)) = &cfg {
+        for (name, dir) in [("data", &cfg.data_dir), ("cache", &cfg.cache_dir)] {
+            let res = if !dir.is_dir() {
+                Err(anyhow!("doesn't exist"))
+            } else if nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK).is_err() {
+                Err(anyhow!("isn't writable"))
+            } else {
+                Ok(())
+            };
+            report(format!("{name} directory {}", dir.display()), res);
+        }
+    }
+    report("ct-ng in PATH".into(), find_in_path("ct-ng")
+        .map(|_| ())
+        .context("not found"));
+    if let Ok((cfg, _)) = &cfg && cfg.iter_toolchains().any(|t| t.lto_enabled) {
+        report("host gcc supports LTO".into(), check_host_lto());
+    }
+    if let Ok((cfg, _)) = &cfg && cfg.iter_toolchains().any(|t| t.graphite_enabled) {
+        report("host ISL headers".into(), check_host_isl());
+    }
+
+    if failed {
+        bail!("Setup is incomplete");
+    }
+    Ok(())
+}
+
+// Compiles `source` with the host gcc, to probe for compiler features and
+// installed headers
+fn host_gcc_compiles(args: &[&str], source: &str) -> Result<()> {
+    let mut child = Command::new("gcc")
+        .args(args)
+        .args(["-x", "c", "-c", "-", "-o", "/dev/null"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("failed to run gcc")?;
+    child.stdin.take().unwrap().write_all(source.as_bytes())?;
+    if !child.wait()?.success() {
+        bail!("gcc {} failed", args.join(" "));
+    }
+    Ok(())
+}
+
+fn check_host_lto() -> Result<()> {
+    host_gcc_compiles(&["-flto"], "")
+}
+
+fn check_host_isl() -> Result<()> {
+    host_gcc_compiles(&[], "#include <isl/version.h>\n")
+        .context("ISL headers not found, is libisl-dev installed?")
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|p| p.is_file())
+}
+
+fn detect_jobs(ct_jobs: Option<&str>, available_parallelism: impl FnOnce() -> std::io::Result<std::num::NonZeroUsize>) -> usize {
+    if let Some(jobs) = ct_jobs.and_then(|j| j.parse().ok()) {
+        log::debug!("Using CT_JOBS={jobs}");
+        return jobs;
+    }
+
+    let cores = available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    log::debug!("Detected {cores} cores");
+    cores
+}
+
+fn print_line_diff(old: &str, new: &str) {
+    use std::collections::HashSet;
+
+    let old_lines: HashSet<&str> = old.lines().collect();
+    let new_lines: HashSet<&str> = new.lines().collect();
+
+    for line in old.lines().filter(|l| !new_lines.contains(l)) {
+        println!("-{line}");
+    }
+    for line in new.lines().filter(|l| !old_lines.contains(l)) {
+        println!("+{line}");
+    }
+}
+
+// Describes how `new` differs from `old`, one line per changed leaf
+fn value_changes(path: &str, old: &toml::Value, new: &toml::Value, out: &mut Vec<String>) {
+    let join = |key: &str| if path.is_empty() { key.to_string() } else { format!("{path}.{key}") };
+    match (old, new) {
+        (toml::Value::Table(old), toml::Value::Table(new)) => {
+            for (key, value) in new {
+                match old.get(key) {
+                    Some(old_value) => value_changes(&join(key), old_value, value, out),
+                    None => out.push(format!("added {} = {}", join(key), value)),
+                }
+            }
+            for key in old.keys().filter(|k| !new.contains_key(*k)) {
+                out.push(format!("removed {}", join(key)));
+            }
+        },
+        (toml::Value::Array(old), toml::Value::Array(new)) if old.len() == new.len() => {
+            for (i, (old, new)) in old.iter().zip(new).enumerate() {
+                value_changes(&join(&i.to_string()), old, new, out);
+            }
+        },
+        (old, new) if old != new => out.push(format!("changed {}: {} -> {}", path, old, new)),
+        _ => (),
+    }
+}
+
+// Options of a ct-ng .config, with unset ones as `None`
+fn parse_ct_config(config: &str) -> BTreeMap<&str, Option<&str>> {
+    config.lines()
+        .filter_map(|line| {
+            if let Some(unset) = line.strip_prefix("# ").and_then(|l| l.strip_suffix(" is not set")) {
+                Some((unset, None))
+            } else if line.starts_with('#') {
+                None
+            } else {
+                line.split_once('=').map(|(k, v)| (k, Some(v)))
+            }
+        })
+        .collect()
+}
+
+fn ct_config_diff(old: &str, new: &str, full: bool) -> Vec<(char, String)> {
+    fn fmt_option(key: &str, value: Option<&str>) -> String {
+        match value {
+            Some(v) => format!("{key}={v}"),
+            None => format!("# {key} is not set"),
+        }
+    }
+
+    let old = parse_ct_config(old);
+    let new = parse_ct_config(new);
+    let keys: BTreeSet<&str> = old.keys().chain(new.keys()).copied().collect();
+
+    let mut out = Vec::new();
+    for key in keys {
+        match (old.get(key), new.get(key)) {
+            (Some(a), Some(b)) if a == b => {
+                if full {
+                    out.push((' ', fmt_option(key, *a)));
+                }
+            },
+            (a, b) => {
+                if let Some(a) = a {
+                    out.push(('-', fmt_option(key, *a)));
+                }
+                if let Some(b) = b {
+                    out.push(('+', fmt_option(key, *b)));
+                }
+            },
+        }
+    }
+    out
+}
+
+// Shell commands by default, or plain data with a non-text --format
+fn print_env(env: &HashMap<String, String>, shell: EnvFormat, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            print!("{}", format_env(env, shell));
+            Ok(())
+        },
+        _ => format.printer().print_env(env),
+    }
+}
+
+fn format_env(env: &HashMap<String, String>, fmt: EnvFormat) -> String {
+    if let EnvFormat::Json = fmt {
+        return serde_json::to_string_pretty(env).unwrap() + "\n";
+    }
+
+    let mut vars: Vec<_> = env.iter().collect();
+    vars.sort();
+    vars.into_iter()
+        .map(|(k, v)| match fmt {
+            EnvFormat::Fish => format!("set -gx {} '{}'\n", k, v.replace('\\', r"\\").replace('\'', r"\'")),
+            _ => format!("export {}='{}'\n", k, v.replace('\'', r"'\''")),
+        })
+        .collect()
+}
+
+enum ShellKind {
+    Posix,
+    Fish,
+    PowerShell,
+}
+
+impl ShellKind {
+    fn detect(shell: &str) -> Self {
+        if shell.ends_with("fish") {
+            Self::Fish
+        } else if shell.ends_with("pwsh") || shell.ends_with("powershell") {
+            Self::PowerShell
+        } else {
+            Self::Posix
+        }
+    }
+    // Environment variable and shell arguments that put `prompt` in front of
+    // the prompt, or next to it for fish
+    fn prompt_setup(self, prompt: &str) -> (Option<String>, Vec<String>) {
+        match self {
+            // PS1 is expanded every time it's shown, so it only refers to the
+            // suffix, and the results of that expansion aren't expanded again
+            Self::Posix => {
+                let prompt = prompt.replace('\\', r"\\")
+                    .replace('"', r#"\""#)
+                    .replace('$', r"\$")
+                    .replace('`', r"\`");
+                let var = format!(r#"PROMPT_COMMAND=if [ "$SET_PS1" != "true" ]; then SET_PS1=true; CHAINED_PROMPT="{}"; PS1="\${{CHAINED_PROMPT}} $PS1"; fi "#, prompt);
+                (Some(var), Vec::new())
+            },
+            // The init command runs after config.fish, so this wins over the
+            // user's own fish_right_prompt
+            Self::Fish => {
+                let prompt = prompt.replace('\\', r"\\").replace('\'', r"\'");
+                let init = format!("function fish_right_prompt; echo '{}'; end", prompt);
+                (None, vec!["-C".into(), init])
+            },
+            // PowerShell's prompt is a function, so wrap whatever the profile set up
+            Self::PowerShell => {
+                let prompt = prompt.replace('\'', "''");
+                let init = format!("$chained_prompt = $function:prompt; function prompt {{ '{} ' + (& $chained_prompt) }}", prompt);
+                (None, vec!["-NoExit".into(), "-Command".into(), init])
+            },
+        }
+    }
+}
+
+fn is_gcc_configure(word: &str) -> bool {
+    word.ends_with("/configure") && word.contains("gcc")
+}
+
+fn split_shell_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut cur = String::new();
+    let mut in_quote = false;
+    for c in line.chars() {
+        match c {
+            '\'' => in_quote = !in_quote,
+            c if c.is_whitespace() && !in_quote => {
+                if !cur.is_empty() {
+                    words.push(std::mem::take(&mut cur));
+                }
+            },
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        words.push(cur);
+    }
+    words
+}
+
+// Bumped whenever the config format changes in a way that needs `migrate`
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    // Configs from before versioning are version 0
+    #[serde(default)]
+    schema_version: u32,
+    cache_dir: PathBuf,
+    data_dir: PathBuf,
+    toolchain: Vec<Toolchain>,
+}
+
+// Toolchain fields that are left out of the config while unset, with a
+// placeholder of the type they're set from
+fn optional_toolchain_field(name: &str) -> Option<toml::Value> {
+    const STRINGS: &[&str] = &[
+        "target_cflags", "target_ldflags", "binutils_src", "glibc_src", "ct_log_level",
+        "ct_mirror", "gdb_version", "strace_version", "downloader", "wget_opts", "curl_opts",
+        "build_triple", "ct_overlay", "kernel_version", "kernel_headers_version", "threads",
+        "target_alias", "stack_protector", "sysroot_dir_prefix", "sysroot_suffix",
+        "local_patch_dir", "target_opt_cflags", "isl_version", "host_triple", "host_sysroot",
+        "cxx_abi",
+    ];
+    const INTEGERS: &[&str] = &["parallel_downloads", "ct_jobs", "fortify_source"];
+    match name {
+        _ if STRINGS.contains(&name) => Some(String::new().into()),
+        _ if INTEGERS.contains(&name) => Some(0.into()),
+        "libc_locales" => Some(false.into()),
+        _ => None,
+    }
+}
+
+impl Config {
+    fn save(&self) -> Result<()> {
+        let to_save = toml::to_string(&self)
+            .context("Failed to serialize config")?;
+        fs::write(Self::path(), &to_save)
+            .context("Failed to save the config")?;
+        Ok(())
+    }
+    fn path() -> PathBuf {
+        let dirs = directories::ProjectDirs::from("", "", "chained").unwrap();
+        dirs.config_local_dir()
+            .join("chained.toml")
+    }
+    fn load() -> Result<(Config, PathBuf)> {
+        let path = Self::path();
+        let cfg_string = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to open and read config file from {}", path.display()))?;
+
+        let me: Config = toml::from_str(&cfg_string)
             .context("Failed to deserialize config file")?;
+        if me.schema_version < SCHEMA_VERSION {
+            log::warn!("{} is in an old format, run `chained migrate` to update it", path.display());
+        }
 
         Ok((me, path))
     }
-    fn find_toolchain(&self, name: &Triple) -> Option<&Toolchain> {
+    // Brings a config of any older version up to the current one, returning
+    // it along with a description of every change
+    fn migrate(old: toml::Value) -> Result<(Config, Vec<String>)> {
+        let version = match old.get("schema_version") {
+            Some(v) => v.as_integer()
+                .and_then(|v| u32::try_from(v).ok())
+                .context("schema_version is not a version number")?,
+            None => 0,
+        };
+        if version > SCHEMA_VERSION {
+            bail!("Config is version {version}, but this chained only knows up to {SCHEMA_VERSION}");
+        }
+
+        let mut value = old.clone();
+        for v in version..SCHEMA_VERSION {
+            match v {
+                // Triples were tables of arch, vendor and os, and new toolchain
+                // fields were missing. Deserializing takes care of both
+                0 => (),
+                _ => unreachable!("no migration from version {v}"),
+            }
+        }
+        if let toml::Value::Table(t) = &mut value {
+            t.insert("schema_version".into(), toml::Value::Integer(SCHEMA_VERSION.into()));
+        }
+
+        let cfg: Config = value.try_into()
+            .context("Failed to deserialize config file")?;
+        let new = toml::Value::try_from(&cfg)
+            .context("Failed to serialize config")?;
+        let mut changes = Vec::new();
+        value_changes("", &old, &new, &mut changes);
+        Ok((cfg, changes))
+    }
+    fn get_field(&self, key: &str) -> Result<toml::Value> {
+        let mut value = toml::Value::try_from(self)
+            .context("Failed to serialize config")?;
+        for part in key.split('.') {
+            value = match value {
+                toml::Value::Table(mut t) => t.remove(part),
+                toml::Value::Array(mut a) => part.parse()
+                    .ok()
+                    .filter(|&i| i < a.len())
+                    .map(|i| a.swap_remove(i)),
+                _ => None,
+            }.with_context(|| format!("No such field {key}"))?;
+        }
+        Ok(value)
+    }
+    fn set_field(&mut self, key: &str, new: &str) -> Result<()> {
+        let mut root = toml::Value::try_from(&*self)
+            .context("Failed to serialize config")?;
+
+        let (parents, last) = match key.rsplit_once('.') {
+            Some((parents, last)) => (Some(parents), last),
+            None => (None, key),
+        };
+        let mut value = &mut root;
+        for part in parents.into_iter().flat_map(|p| p.split('.')) {
+            value = match value {
+                toml::Value::Table(t) => t.get_mut(part),
+                toml::Value::Array(a) => part.parse::<usize>()
+                    .ok()
+                    .and_then(|i| a.get_mut(i)),
+                _ => None,
+            }.with_context(|| format!("No such field {key}"))?;
+        }
+        // Unset Option fields aren't serialized, so they have to be known by name
+        let in_toolchain = parents.is_some_and(|p| p.split_once('.')
+            .is_some_and(|(first, idx)| first == "toolchain" && idx.parse::<usize>().is_ok()));
+        let slot = match value {
+            toml::Value::Table(t) => match t.entry(last) {
+                toml::map::Entry::Occupied(e) => e.into_mut(),
+                toml::map::Entry::Vacant(e) => {
+                    let placeholder = in_toolchain
+                        .then(|| optional_toolchain_field(last))
+                        .flatten()
+                        .with_context(|| format!("No such field {key}"))?;
+                    e.insert(placeholder)
+                },
+            },
+            toml::Value::Array(a) => last.parse::<usize>()
+                .ok()
+                .and_then(|i| a.get_mut(i))
+                .with_context(|| format!("No such field {key}"))?,
+            _ => bail!("No such field {key}"),
+        };
+
+        *slot = match slot {
+            toml::Value::String(_) => new.into(),
+            toml::Value::Integer(_) => new.parse::<i64>()
+                .with_context(|| format!("{new} is not an integer"))?
+                .into(),
+            toml::Value::Boolean(_) => new.parse::<bool>()
+                .with_context(|| format!("{new} is not a boolean"))?
+                .into(),
+            _ => bail!("Field {key} can't be set from the command line"),
+        };
+
+        *self = root.try_into()
+            .with_context(|| format!("Invalid value {new} for {key}"))?;
+        Ok(())
+    }
+    fn iter_toolchains(&self) -> impl Iterator<Item = &Toolchain> {
         self.toolchain.iter()
+    }
+    fn find_toolchain(&self, name: &Triple) -> Option<&Toolchain> {
+        self.iter_toolchains()
+            .find(|toolchain| toolchain.triple == *name)
+    }
+    fn find_toolchain_mut(&mut self, name: &Triple) -> Option<&mut Toolchain> {
+        self.toolchain.iter_mut()
             .find(|toolchain| toolchain.triple == *name)
     }
+    fn find_toolchain_fuzzy(&self, name: &Triple) -> Result<Option<&Toolchain>> {
+        if let Some(t) = self.find_toolchain(name) {
+            return Ok(Some(t));
+        }
+
+        let normalized = name.normalize();
+        let matches: Vec<&Toolchain> = self.iter_toolchains()
+            .filter(|toolchain| toolchain.triple.normalize() == normalized)
+            .collect();
+
+        match matches.as_slice() {
+            [] => Ok(None),
+            [t] => {
+                eprintln!("Note: treating '{}' as '{}'", name, t.triple);
+                Ok(Some(t))
+            },
+            _ => {
+                eprintln!("'{}' matches multiple toolchains:", name);
+                for t in matches.iter() {
+                    eprintln!("\t{}", t.triple);
+                }
+                bail!("Ambiguous toolchain {}", name);
+            },
+        }
+    }
+}
+
+// Completes the triples of configured toolchains, and nothing before setup
+struct TripleCompleter;
+
+impl ValueCompleter for TripleCompleter {
+    fn complete(&self, current: &OsStr) -> Vec<CompletionCandidate> {
+        let Some(current) = current.to_str() else {
+            return Vec::new();
+        };
+        let Ok((cfg, _)) = Config::load() else {
+            return Vec::new();
+        };
+        cfg.iter_toolchains()
+            .filter(|t| t.triple.to_string().starts_with(current))
+            .map(|t| CompletionCandidate::new(t.triple.to_string())
+                .help(Some(t.triple.description().into())))
+            .collect()
+    }
 }
 
 fn main() -> Result<()> {
+    // Shell completion, e.g. `source <(COMPLETE=bash chained)`
+    clap_complete::CompleteEnv::with_factory(Args::command).complete();
     let args = Args::parse();
 
     env_logger::builder()
@@ -249,14 +2432,31 @@ fn main() -> Result<()> {
         })
     .init();
 
+    let _ = CT_NG_EXTRA_ENV.set(args.ct_ng_extra_args);
+
+    let format = args.format;
     match args.cmd {
-        Commands::Setup => {
+        Commands::Setup { check: true, .. } => check_setup(),
+        Commands::Setup { data_dir, cache_dir, shared, check: false } => {
             let dirs = directories::ProjectDirs::from("", "", "chained")
                 .unwrap();
 
+            let (default_data_dir, default_cache_dir): (PathBuf, PathBuf) = if shared {
+                ("/var/lib/chained".into(), "/var/cache/chained".into())
+            } else {
+                (dirs.data_local_dir().into(), dirs.cache_dir().into())
+            };
+            let data_dir = data_dir.unwrap_or(default_data_dir);
+            let cache_dir = cache_dir.unwrap_or(default_cache_dir);
+            for d in [&data_dir, &cache_dir] {
+                if !d.is_absolute() {
+                    bail!("{} is not an absolute path", d.display());
+                }
+            }
+
             let create_dirs = [
-                dirs.cache_dir(),
-                dirs.data_local_dir(),
+                cache_dir.as_path(),
+                data_dir.as_path(),
                 dirs.config_local_dir(),
             ];
             for d in create_dirs.iter() {
@@ -264,16 +2464,25 @@ fn main() -> Result<()> {
                 if d.exists() {
                     let is_file = if d.is_file() { " and is a file" } else {""};
                     log::warn!("{} already exists{is_file}", d.display());
+                    if d.is_dir() && nix::unistd::access(*d, nix::unistd::AccessFlags::W_OK).is_err() {
+                        bail!("{} exists but is not writable", d.display());
+                    }
                     continue;
                 }
-                fs::create_dir_all(&d)
+                fs::create_dir_all(d)
                     .with_context(|| format!("Failed to create {} dir", d.display()))?;
+                // Lets remove tell the directories chained made from ones the user had
+                if *d != dirs.config_local_dir() {
+                    fs::write(d.join(CREATED_MARKER), "")
+                        .with_context(|| format!("Failed to mark {} as created by chained", d.display()))?;
+                }
             }
 
             let path = Config::path();
             let config = Config {
-                cache_dir: dirs.cache_dir().into(),
-                data_dir: dirs.data_local_dir().into(),
+                schema_version: SCHEMA_VERSION,
+                cache_dir,
+                data_dir,
                 toolchain: Vec::new(),
             };
             let string = toml::to_string(&config)
@@ -290,21 +2499,29 @@ fn main() -> Result<()> {
 
             Ok(())
         },
-        Commands::Remove => {
+        Commands::Remove { keep_config, dry_run } => {
             let dirs = directories::ProjectDirs::from("", "", "chained")
                 .unwrap();
 
-            let remove_dirs = [
-                dirs.cache_dir(),
-                dirs.data_local_dir(),
-                dirs.config_local_dir(),
+            let defaults = vec![
+                dirs.cache_dir().into(),
+                dirs.data_local_dir().into(),
             ];
+            let cfg = Config::load().ok().map(|(cfg, _)| cfg);
+            let mut remove_dirs = removable_dirs(defaults, cfg.as_ref());
+            if !keep_config {
+                remove_dirs.push(dirs.config_local_dir().into());
+            }
+
             for d in remove_dirs.iter() {
                 if d.exists() {
                     if d.is_dir() {
+                        if dry_run {
+                            println!("Would remove {}", d.display());
+                            continue;
+                        }
                         log::debug!("Removing {}", d.display());
-                        fs::remove_dir_all(&d)
-                            .with_context(|| format!("Failed to remove {}", d.display()))?;
+                        remove_dir_all_forced(d)?;
                     } else {
                         log::warn!("Not removing {}, not a directory?", d.display());
                     }
@@ -314,35 +2531,296 @@ fn main() -> Result<()> {
             Ok(())
         },
         Commands::Toolchain { target, cmd } => {
-            let (cfg, _) = Config::load()
+            let (mut cfg, _) = Config::load()
                 .context("Failed to load config file, have you tried running setup?")?;
             match cmd {
-                TargetCmd::Add { gcc_src, inspect } => {
+                TargetCmd::Add {
+                    gcc_src,
+                    inspect,
+                    target_cflags,
+                    target_ldflags,
+                    from_sample,
+                    no_host_shared,
+                    enable_cxx,
+                    binutils_src,
+                    glibc_src,
+                    parallel_download,
+                    ct_log_level,
+                    ct_mirror,
+                    with_newlib,
+                    ct_config_fragments,
+                    gdb_version,
+                    no_gdb,
+                    with_strace,
+                    strace_version,
+                    downloader,
+                    wget_opts,
+                    curl_opts,
+                    build_triple,
+                    ct_jobs,
+                    step_by_step,
+                    ct_overlay,
+                    gcc_config_extra,
+                    libc_extra_config,
+                    kernel_version,
+                    kernel_headers_version,
+                    debug_info,
+                    offline,
+                    host_lib_version,
+                    binutils_extra_config,
+                    threads,
+                    target_alias,
+                    fortify_source,
+                    stack_protector,
+                    static_toolchain,
+                    lto,
+                    sysroot_dir_prefix,
+                    sysroot_suffix,
+                    update_gcc_src,
+                    allow_root,
+                    local_patch_dir,
+                    target_opt_cflags,
+                    with_graphite,
+                    isl_version,
+                    with_fortran,
+                    with_tsan,
+                    sanitizer,
+                    multilib,
+                    libc_locales,
+                    nptl,
+                    strip_host,
+                    debug_at_step,
+                    host_triple,
+                    host_sysroot,
+                    cxx_abi,
+                } => {
+                    if let Some(src) = update_gcc_src {
+                        let Some(triple) = cfg.find_toolchain_fuzzy(&target)?.map(|t| t.triple.clone()) else {
+                            bail!("Toolchain {} not found", target);
+                        };
+                        cfg.find_toolchain_mut(&triple).unwrap().gcc_src = src;
+                        // compile reuses the .config, so it has to pick up the new source now
+                        let t = cfg.find_toolchain(&triple).unwrap();
+                        let hash = if let Some(sample) = &t.from_sample {
+                            t.load_sample(sample, &cfg)
+                                .context("Failed to configure toolchain from sample")?;
+                            None
+                        } else {
+                            t.defconfig(&cfg)
+                                .context("Failed to configure toolchain")?;
+                            Some(t.crosstool_config_hash(&cfg))
+                        };
+                        cfg.find_toolchain_mut(&triple).unwrap().config_hash = hash;
+                        cfg.save()
+                            .context("Failed to save the new config")?;
+                        println!("Updated gcc_src; run `toolchain {} compile` to rebuild", triple);
+                        return Ok(());
+                    }
+
+                    if let Some(sample) = &from_sample {
+                        let sample_triple: Triple = sample.parse()
+                            .map_err(|e| anyhow!("{e}"))
+                            .with_context(|| format!("Sample {sample} is not a valid triple"))?;
+                        if sample_triple != target {
+                            bail!("Sample {sample} does not match the target {target}");
+                        }
+                    }
+
+                    if let Err(warnings) = target.validate() {
+                        for w in warnings {
+                            log::warn!("{w}");
+                        }
+                    }
+
+                    let target = if with_newlib {
+                        target.with_bare_metal_libc(BareMetalLibc::Newlib)
+                            .with_context(|| format!("Can't use newlib with {target}, only bare-metal ELF targets support it"))?
+                    } else {
+                        target
+                    };
+                    if cfg.find_toolchain(&target).is_some() {
+                        bail!("Toolchain {} already exists, remove it first or use --update-gcc-src", target);
+                    }
+
+                    // Fragments are read again on every defconfig, possibly from another directory
+                    let ct_config_fragments = ct_config_fragments.iter()
+                        .map(|f| fs::canonicalize(f)
+                            .with_context(|| format!("Config fragment {} not found", f.display())))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    let ct_overlay = ct_overlay.map(|o| fs::canonicalize(&o)
+                        .with_context(|| format!("Overlay directory {} not found", o.display())))
+                        .transpose()?;
+                    let host_sysroot = host_sysroot.map(|s| fs::canonicalize(&s)
+                        .with_context(|| format!("Host sysroot {} not found", s.display())))
+                        .transpose()?;
+                    if let Some(sysroot) = &host_sysroot && !sysroot.join("usr/lib/pkgconfig").is_dir() {
+                        bail!("Host sysroot {} has no usr/lib/pkgconfig directory", sysroot.display());
+                    }
+                    let local_patch_dir = local_patch_dir.map(|d| fs::canonicalize(&d)
+                        .with_context(|| format!("Local patch directory {} not found", d.display())))
+                        .transpose()?;
+
+                    let mut sanitizers = sanitizer;
+                    if with_tsan && !sanitizers.contains(&Sanitizer::Thread) {
+                        sanitizers.push(Sanitizer::Thread);
+                    }
+
                     let tgt_dir: PathBuf = target.to_string().into();
                     let basedir: PathBuf = cfg.data_dir.join(tgt_dir);
-                    let new = Toolchain {
+                    let mut new = Toolchain {
                         triple: target.clone(),
                         basedir: basedir.clone(),
                         gcc_src,
                         json_spec: basedir.join("target.json"),
                         prefix: basedir.join("prefix"),
+                        target_cflags,
+                        target_ldflags,
+                        host_shared: !no_host_shared,
+                        cxx_enabled: enable_cxx,
+                        binutils_src,
+                        glibc_src,
+                        parallel_downloads: parallel_download,
+                        custom_env: Vec::new(),
+                        ct_log_level,
+                        ct_mirror,
+                        ct_config_fragments,
+                        gdb_enabled: !no_gdb,
+                        gdb_version,
+                        strace_enabled: with_strace,
+                        strace_version,
+                        downloader,
+                        wget_opts,
+                        curl_opts,
+                        build_triple,
+                        ct_jobs,
+                        save_steps: step_by_step,
+                        ct_overlay,
+                        gcc_config_extra,
+                        libc_extra_config,
+                        kernel_version,
+                        kernel_headers_version,
+                        debug_build: debug_info,
+                        offline,
+                        host_lib_versions: host_lib_version.into_iter().collect(),
+                        binutils_extra_config,
+                        threads,
+                        target_alias: target_alias.map(|a| a.trim_end_matches('-').to_string()),
+                        fortify_source,
+                        stack_protector,
+                        static_toolchain,
+                        lto_enabled: lto,
+                        sysroot_dir_prefix,
+                        sysroot_suffix,
+                        allow_root,
+                        local_patch_dir,
+                        target_opt_cflags,
+                        graphite_enabled: with_graphite,
+                        isl_version,
+                        fortran_enabled: with_fortran,
+                        sanitizers,
+                        config_hash: None,
+                        multilib,
+                        libc_locales,
+                        nptl,
+                        strip_host,
+                        debug_pause_steps: debug_at_step,
+                        host_triple,
+                        host_sysroot,
+                        cxx_abi,
+                        from_sample: from_sample.clone(),
                     };
+                    if new.parallel_downloads.is_some() {
+                        log::warn!("crosstool-ng has no parallel download option, --parallel-download will likely be ignored");
+                    }
+                    if *new.triple.arch() == Arch::NiosII {
+                        log::warn!("NIOS II support in crosstool-ng is experimental");
+                    }
+                    if !new.sanitizers.is_empty() {
+                        if !new.triple.is_linux() {
+                            bail!("Sanitizers need a Linux target, {} isn't one", new.triple);
+                        }
+                        log::warn!("Sanitizers in cross-compilers are experimental");
+                        if new.sanitizers.contains(&Sanitizer::Thread) && !new.triple.bits().contains(Bits::B_64) {
+                            log::warn!("ThreadSanitizer only supports 64-bit targets");
+                        }
+                    }
+                    if !new.nptl && !new.triple.is_glibc() {
+                        log::warn!("{} doesn't use glibc, ignoring --nptl false", new.triple);
+                    }
+                    if new.libc_locales.is_some() && !new.triple.is_glibc() {
+                        log::warn!("{} doesn't use glibc, ignoring --libc-locales", new.triple);
+                    }
+                    if new.fortran_enabled && !new.triple.is_linux() {
+                        log::warn!("Fortran on bare-metal {} is unusual, libgfortran needs a fairly complete C library", new.triple);
+                    }
+                    if !new.allow_root && nix::unistd::getuid().is_root() {
+                        log::warn!("Running as root, crosstool-ng will refuse to build without --allow-root");
+                    }
+                    if new.sysroot_dir_prefix.is_some() || new.sysroot_suffix.is_some() {
+                        log::warn!("A custom sysroot layout isn't detected by chained, QEMU_LD_PREFIX will point at {}", new.sysroot_path().display());
+                    }
+                    if new.multilib {
+                        log::warn!("Multilib toolchains take significantly longer to build");
+                    }
+                    if new.lto_enabled {
+                        log::warn!("LTO makes building the toolchain take a lot more memory and time");
+                    }
+                    if new.static_toolchain {
+                        log::warn!("Static toolchains take longer to build and have larger binaries");
+                    }
+                    if new.cxx_abi.is_some() && !new.cxx_enabled {
+                        bail!("--cxx-abi needs C++ support, but --enable-c++ is false");
+                    }
+                    // libc++ is built by LLVM, not by crosstool-ng
+                    if new.cxx_abi() == CxxAbi::Libcxx {
+                        bail!("libc++ needs an LLVM based toolchain, which isn't supported yet");
+                    }
+                    // There are no Windows targets (yet)
+                    if new.threads == Some(GccThreads::Win32) {
+                        bail!("{} isn't a Windows target, win32 threads aren't supported", new.triple);
+                    }
+                    if new.glibc_src.is_some() && !new.triple.is_glibc() {
+                        log::warn!("{} doesn't use glibc, ignoring --glibc-src", new.triple);
+                    }
+                    if (new.kernel_version.is_some() || new.kernel_headers_version.is_some()) && !new.triple.is_linux() {
+                        log::warn!("{} isn't a Linux target, ignoring the kernel version", new.triple);
+                    }
+                    if !new.libc_extra_config.is_empty() && new.triple.libc_extra_config_key().is_none() {
+                        log::warn!("{} has no C library, ignoring --libc-extra-config", new.triple);
+                    }
+                    for flags in [&new.target_cflags, &new.target_opt_cflags, &new.target_ldflags].into_iter().flatten() {
+                        new.warn_flag_conflicts(flags);
+                    }
 
-                    let mut cfg = cfg;
+                    // Samples generate their .config through ct-ng, not from crosstool_config
+                    if from_sample.is_none() {
+                        new.config_hash = Some(new.crosstool_config_hash(&cfg));
+                    }
+
+                    let idx = cfg.toolchain.len();
                     cfg.toolchain.push(new);
                     cfg.save()
                         .context("Failed to save the new config")?;
-                    let new = cfg.find_toolchain(&target).unwrap();
+                    let new = &cfg.toolchain[idx];
 
                     log::debug!("Adding {:#?}", new);
 
-                    new.defconfig(&cfg)
-                        .context("Failed to configure new toolchain")?;
+                    new.create_basedir()?;
+                    new.install_overlay()
+                        .context("Failed to copy the overlay directory")?;
+                    if let Some(sample) = &from_sample {
+                        new.load_sample(sample, &cfg)
+                            .context("Failed to configure new toolchain from sample")?;
+                    } else {
+                        new.defconfig(&cfg)
+                            .context("Failed to configure new toolchain")?;
+                    }
                     if inspect {
                         new.nconfig()
                             .context("Failed to nconfig new toolchain")?;
                     }
-                    new.compile()
+                    new.compile(None, None)
                         .context("Failed to compile new toolchain")?;
 
                     println!("Toolchain {} installed correctly", target);
@@ -350,53 +2828,899 @@ fn main() -> Result<()> {
                     Ok(())
                 },
                 TargetCmd::Reconfigure => {
-                    if let Some(t) = cfg.find_toolchain(&target) {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.check_overlay();
+                        t.nconfig()
+                            .context("Failed to nconfig toolchain")?;
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+
+                    Ok(())
+                },
+                TargetCmd::Rebuild { inspect, also_clean_prefix } => {
+                    let Some(t) = cfg.find_toolchain_fuzzy(&target)? else {
+                        bail!("Toolchain {} not found", target);
+                    };
+                    t.ct_ng("clean")
+                        .context("Failed to clean the build directory")?;
+                    if also_clean_prefix && t.prefix.exists() {
+                        log::debug!("Removing {}", t.prefix.display());
+                        remove_dir_all_forced(&t.prefix)?;
+                        fs::create_dir_all(&t.prefix)
+                            .with_context(|| format!("Failed to recreate {}", t.prefix.display()))?;
+                    }
+                    let triple = t.triple.clone();
+                    if let Some(sample) = &t.from_sample {
+                        t.load_sample(sample, &cfg)
+                            .context("Failed to configure toolchain from sample")?;
+                    } else {
+                        t.defconfig(&cfg)
+                            .context("Failed to configure toolchain")?;
+                        let hash = t.crosstool_config_hash(&cfg);
+                        cfg.find_toolchain_mut(&triple).unwrap().config_hash = Some(hash);
+                        cfg.save()
+                            .context("Failed to save the new config")?;
+                    }
+                    let t = cfg.find_toolchain(&triple).unwrap();
+                    if inspect {
+                        t.nconfig()
+                            .context("Failed to nconfig toolchain")?;
+                    }
+                    t.compile(None, None)
+                        .context("Failed to compile toolchain")?;
+
+                    println!("Toolchain {} rebuilt correctly", t.triple);
+                    Ok(())
+                },
+                TargetCmd::Step { step_name } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.compile_step(&step_name, None)
+                            .with_context(|| format!("Failed to run step {step_name}"))?;
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+
+                    Ok(())
+                },
+                TargetCmd::ListSteps => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.ct_ng("list-steps")
+                            .context("Failed to list build steps")?;
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+
+                    Ok(())
+                },
+                TargetCmd::Diff { other, full } => {
+                    let Some(t) = cfg.find_toolchain_fuzzy(&target)? else {
+                        bail!("Toolchain {} not found", target);
+                    };
+                    let Some(o) = cfg.find_toolchain_fuzzy(&other)? else {
+                        bail!("Toolchain {} not found", other);
+                    };
+                    let ours = t.read_ct_config()?;
+                    let theirs = o.read_ct_config()?;
+
+                    let color = std::io::stdout().is_terminal();
+                    for (sign, line) in ct_config_diff(&ours, &theirs, full) {
+                        match (sign, color) {
+                            ('-', true) => println!("\x1b[31m-{line}\x1b[0m"),
+                            ('+', true) => println!("\x1b[32m+{line}\x1b[0m"),
+                            _ => println!("{sign}{line}"),
+                        }
+                    }
+
+                    Ok(())
+                },
+                TargetCmd::Show { flags, env, ct_config, all, ct_option, security } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        if ct_config {
+                            let config = t.read_ct_config()?;
+                            for line in config.lines() {
+                                if all || !(line.is_empty() || line.starts_with('#') || line.ends_with("=n")) {
+                                    println!("{line}");
+                                }
+                            }
+                            return Ok(());
+                        }
+                        if let Some(key) = ct_option {
+                            let config = t.read_ct_config()?;
+                            let options = parse_ct_config(&config);
+                            let with_prefix = format!("CT_{key}");
+                            let value = options.get(key.as_str())
+                                .or_else(|| options.get(with_prefix.as_str()));
+                            match value {
+                                Some(Some(v)) => println!("{v}"),
+                                Some(None) => println!("n"),
+                                None => bail!("{key} is not a crosstool-ng option of {}", t.triple),
+                            }
+                            return Ok(());
+                        }
+                        if flags {
+                            for flag in t.gcc_configure_flags()? {
+                                println!("{flag}");
+                            }
+                            return Ok(());
+                        }
+                        if env {
+                            format.printer().print_env(&t.env_map()?)?;
+                            return Ok(());
+                        }
+                        if security {
+                            match t.fortify_source {
+                                Some(level) => println!("_FORTIFY_SOURCE: {level}"),
+                                None => println!("_FORTIFY_SOURCE: not set"),
+                            }
+                            match t.stack_protector {
+                                Some(protector) => println!("stack protector: {protector}"),
+                                None => println!("stack protector: compiler default"),
+                            }
+                            println!("target CFLAGS: {}", t.target_cflags().unwrap_or_default());
+                            return Ok(());
+                        }
+                        format.printer().print_toolchain(t)?;
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::Shell { prompt_suffix, no_prompt_change, require_installed } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        if !t.is_installed() {
+                            let bin = t.prefix.join("bin");
+                            if require_installed {
+                                bail!("Toolchain binaries not found at {}, run `toolchain compile` first", bin.display());
+                            }
+                            log::warn!("Toolchain binaries not found at {}. Did you forget to run `toolchain compile`?", bin.display());
+                        }
+                        let prompt = (!no_prompt_change).then_some(prompt_suffix.as_str());
+                        t.shell(prompt)?
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::JsonSpec { extra_pre_link_args, extra_post_link_args, link_env } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.write_json_spec(&extra_pre_link_args, &extra_post_link_args, &link_env)?;
+                        println!("Wrote JSON target specification to {}", t.json_spec.display());
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::BuildStats => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.build_stats()?;
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::UpdateSample => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.update_sample(&cfg)
+                            .context("Failed to update toolchain from sample")?;
                         t.nconfig()
                             .context("Failed to nconfig toolchain")?;
+                        t.compile(None, None)
+                            .context("Failed to compile toolchain")?;
                     } else {
                         bail!("Toolchain {} not found", target);
                     }
+                    Ok(())
+                },
+                TargetCmd::Compile { jobs, ct_log_level, color, no_color, only_step } => {
+                    let color = match (color, no_color) {
+                        (_, true) => Some(false),
+                        (true, false) => Some(true),
+                        (false, false) => None,
+                    };
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.warn_stale_config(&cfg);
+                        if let Some(level) = ct_log_level {
+                            t.set_ct_log_level(level)
+                                .context("Failed to set crosstool-ng log level")?;
+                        }
+                        if let Some(step) = only_step {
+                            t.compile_step(&step, color)
+                                .with_context(|| format!("Failed to run step {step}"))?;
+                        } else {
+                            t.compile(jobs, color)
+                                .context("Failed to compile toolchain")?;
+                        }
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::Download => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.download()?;
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::Inspect { tool } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.inspect(tool)?;
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::AddEnv { key, value } => {
+                    let Some(triple) = cfg.find_toolchain_fuzzy(&target)?.map(|t| t.triple.clone()) else {
+                        bail!("Toolchain {} not found", target);
+                    };
+                    let t = cfg.find_toolchain_mut(&triple).unwrap();
+
+                    if let Some(existing) = t.custom_env.iter_mut().find(|(k, _)| *k == key) {
+                        existing.1 = value;
+                    } else {
+                        t.custom_env.push((key, value));
+                    }
+                    cfg.save()
+                        .context("Failed to save the new config")?;
+                    Ok(())
+                },
+                TargetCmd::RemoveEnv { key } => {
+                    let Some(triple) = cfg.find_toolchain_fuzzy(&target)?.map(|t| t.triple.clone()) else {
+                        bail!("Toolchain {} not found", target);
+                    };
+                    let t = cfg.find_toolchain_mut(&triple).unwrap();
 
+                    let len = t.custom_env.len();
+                    t.custom_env.retain(|(k, _)| *k != key);
+                    if t.custom_env.len() == len {
+                        log::warn!("{key} was not set for {triple}");
+                    }
+                    cfg.save()
+                        .context("Failed to save the new config")?;
                     Ok(())
                 },
-                TargetCmd::Show => {
-                    if let Some(t) = cfg.find_toolchain(&target) {
-                        println!("Toolchain triple {}:", t.triple);
-                        println!("\tJSON target specification path: {}", t.json_spec.display());
-                        println!("\tbase directory path: {}", t.basedir.display());
-                        println!("\tprefix path: {}", t.prefix.display());
+                TargetCmd::Run { cmd } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.run(&cmd)?;
                     } else {
                         bail!("Toolchain {} not found", target);
                     }
                     Ok(())
                 },
-                TargetCmd::Shell => {
-                    if let Some(t) = cfg.find_toolchain(&target) {
-                        t.shell()?
+                TargetCmd::Env { shell } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        print_env(&t.env_map()?, shell, format)?;
                     } else {
                         bail!("Toolchain {} not found", target);
                     }
                     Ok(())
                 },
-                _ => todo!(),
+                TargetCmd::CargoConfig => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        print!("{}", t.cargo_config()?);
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+                TargetCmd::Pack { output, relocatable } => {
+                    if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                        t.pack(&output, relocatable)?;
+                        println!("SDK written to {}", output.display());
+                    } else {
+                        bail!("Toolchain {} not found", target);
+                    }
+                    Ok(())
+                },
+            }
+        },
+        Commands::Which { tool, target } => {
+            let (cfg, _) = Config::load()
+                .context("Failed to load config file, have you tried running setup?")?;
+
+            if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                let path = t.resolve_tool(&tool)
+                    .with_context(|| format!("{tool} not found in toolchain {}", t.triple))?;
+                println!("{}", path.display());
+            } else {
+                bail!("Toolchain {} not found", target);
+            }
+
+            Ok(())
+        },
+        Commands::Migrate { dry_run } => {
+            let path = Config::path();
+            let old = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to open and read config file from {}", path.display()))?;
+            let old: toml::Value = toml::from_str(&old)
+                .context("Failed to parse config file")?;
+
+            let (cfg, changes) = Config::migrate(old)?;
+            if changes.is_empty() {
+                println!("{} is already up to date", path.display());
+                return Ok(());
+            }
+            for change in changes.iter() {
+                println!("{change}");
+            }
+            if !dry_run {
+                cfg.save()
+                    .context("Failed to save the migrated config")?;
+                println!("Migrated {} to version {SCHEMA_VERSION}", path.display());
+            }
+            Ok(())
+        },
+        Commands::Config { cmd } => {
+            let (mut cfg, _) = Config::load()
+                .context("Failed to load config file, have you tried running setup?")?;
+
+            match cmd {
+                ConfigCmd::Get { key } => match cfg.get_field(&key)? {
+                    toml::Value::String(s) => println!("{s}"),
+                    toml::Value::Table(t) => print!("{}", toml::to_string(&t)?),
+                    v => println!("{v}"),
+                },
+                ConfigCmd::Set { key, value } => {
+                    cfg.set_field(&key, &value)?;
+                    cfg.save()
+                        .context("Failed to save the new config")?;
+                },
+            }
+
+            Ok(())
+        },
+        Commands::Env { target, shell } => {
+            let (cfg, _) = Config::load()
+                .context("Failed to load config file, have you tried running setup?")?;
+
+            if let Some(t) = cfg.find_toolchain_fuzzy(&target)? {
+                print_env(&t.env_map()?, shell, format)?;
+            } else {
+                bail!("Toolchain {} not found", target);
             }
+
+            Ok(())
+        },
+        Commands::List { json, verbose } => {
+            let (cfg, _) = Config::load()
+                .context("Failed to load config file, have you tried running setup?")?;
+
+            let format = if json { OutputFormat::Json } else { format };
+            format.printer().print_toolchains(&cfg.toolchain, verbose)?;
+
+            Ok(())
         },
         Commands::Show => {
             let (cfg, path) = Config::load()
                 .context("Failed to load config file, have you tried running setup?")?;
 
-            println!("Read config from {}", path.display());
-            println!("Cache directory: {}", cfg.cache_dir.display());
-            println!("Data directory: {}", cfg.data_dir.display());
-            for tgt in cfg.toolchain.iter() {
-                println!();
-                println!("Toolchain triple {}:", tgt.triple);
-                println!("\tJSON target specification path: {}", tgt.json_spec.display());
-                println!("\tbase directory path: {}", tgt.basedir.display());
-                println!("\tprefix path: {}", tgt.prefix.display());
-            }
+            format.printer().print_config(&cfg, &path)?;
 
             Ok(())
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_ct_ng_status, copy_dir_all, ct_config_diff, detect_jobs, dir_contents, format_env, is_history_log, parse_env_assignment, parse_host_lib_version, parse_list_steps, colorize_build_line, CxxAbi, EnvFormat, GccThreads, Sanitizer, ShellKind, StackProtector, Config, Toolchain, Triple};
+    use std::collections::HashMap;
+    use std::io;
+    use std::num::NonZeroUsize;
+    use std::path::Path;
+
+    fn config() -> Config {
+        Config {
+            schema_version: super::SCHEMA_VERSION,
+            cache_dir: "/chained/cache".into(),
+            data_dir: "/chained".into(),
+            toolchain: Vec::new(),
+        }
+    }
+
+    fn toolchain(triple: &str) -> Toolchain {
+        let triple: Triple = triple.parse().unwrap();
+        serde_json::from_value(serde_json::json!({
+            "triple": triple,
+            "gcc_src": "https://github.com/rust-lang/gcc.git",
+            "basedir": "/chained/base",
+            "json_spec": "/chained/base/target.json",
+            "prefix": "/chained/base/prefix",
+        })).unwrap()
+    }
+
+    fn cores(n: usize) -> impl FnOnce() -> io::Result<NonZeroUsize> {
+        move || Ok(NonZeroUsize::new(n).unwrap())
+    }
+
+    #[test]
+    fn jobs_from_available_parallelism() {
+        assert_eq!(detect_jobs(None, cores(8)), 8);
+        assert_eq!(detect_jobs(None, || Err(io::Error::other("unsupported"))), 1);
+    }
+
+    #[test]
+    fn ct_jobs_overrides_detection() {
+        assert_eq!(detect_jobs(Some("3"), cores(8)), 3);
+        assert_eq!(detect_jobs(Some("garbage"), cores(8)), 8);
+    }
+
+    #[test]
+    fn env_map_for_each_os() {
+        for (triple, linker_var, linker) in [
+            ("aarch64-unknown-linux-gnu", "CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_LINKER", "aarch64-unknown-linux-gnu-gcc"),
+            ("mips-unknown-linux-musl", "CARGO_TARGET_MIPS_UNKNOWN_LINUX_MUSL_LINKER", "mips-unknown-linux-musl-gcc"),
+            ("m68k-unknown-linux-uclibc", "CARGO_TARGET_M68K_UNKNOWN_LINUX_UCLIBC_LINKER", "m68k-unknown-linux-uclibc-gcc"),
+            ("sh3-unknown-elf", "CARGO_TARGET_SH3_UNKNOWN_NONE_ELF_LINKER", "sh3-unknown-elf-gcc"),
+        ] {
+            let t = toolchain(triple);
+            let vars = t.env_map().unwrap();
+            assert!(vars["PATH"].starts_with("/chained/base/prefix/bin:"));
+            assert!(vars["LD_LIBRARY_PATH"].starts_with("/chained/base/prefix/lib"));
+            assert_eq!(vars["QEMU_LD_PREFIX"], t.sysroot_path().display().to_string());
+            assert_eq!(vars[linker_var], linker);
+        }
+    }
+
+    #[test]
+    fn history_log_names() {
+        assert!(is_history_log("build-1760400000.log"));
+        assert!(!is_history_log("build.log"));
+        assert!(!is_history_log("build-output.log"));
+        assert!(!is_history_log("build-.log"));
+        assert!(!is_history_log("build-old.log"));
+    }
+
+    #[test]
+    fn gdb_config() {
+        let cfg = config();
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        assert!(!t.crosstool_config(&cfg).contains("CT_DEBUG_GDB"));
+
+        t.gdb_version = Some("14.2".into());
+        let ct = t.crosstool_config(&cfg);
+        assert!(ct.contains("CT_DEBUG_GDB=y\n"));
+        assert!(ct.contains("CT_GDB_VERSION=\"14.2\"\n"));
+
+        t.gdb_enabled = false;
+        let ct = t.crosstool_config(&cfg);
+        assert!(ct.contains("CT_DEBUG_GDB=n\n"));
+        assert!(!ct.contains("CT_GDB_VERSION"));
+    }
+
+    #[test]
+    fn overlay_copy_and_compare() {
+        let tmp = std::env::temp_dir().join(format!("chained-overlay-{}", std::process::id()));
+        let (orig, copy) = (tmp.join("orig"), tmp.join("copy"));
+        std::fs::create_dir_all(orig.join("gcc/15.1.0")).unwrap();
+        std::fs::write(orig.join("gcc/15.1.0/0001-fix.patch"), "old").unwrap();
+
+        copy_dir_all(&orig, &copy).unwrap();
+        assert_eq!(dir_contents(&orig).unwrap(), dir_contents(&copy).unwrap());
+
+        std::fs::write(orig.join("gcc/15.1.0/0001-fix.patch"), "new").unwrap();
+        assert_ne!(dir_contents(&orig).unwrap(), dir_contents(&copy).unwrap());
+
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+
+    #[test]
+    fn read_only_prefix_is_removed() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = std::env::temp_dir().join(format!("chained-prefix-{}", std::process::id()));
+        std::fs::create_dir_all(tmp.join("bin")).unwrap();
+        std::fs::write(tmp.join("bin/gcc"), "").unwrap();
+        for dir in [tmp.join("bin"), tmp.clone()] {
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+        }
+
+        super::remove_dir_all_forced(&tmp).unwrap();
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn ct_config_diffs() {
+        let old = "\
+# Comment
+CT_ARCH_ARM=y
+CT_ARCH_64=y
+CT_GCC_VERSION=\"15.1.0\"
+# CT_CC_LANG_CXX is not set
+";
+        let new = "\
+CT_ARCH_ARM=y
+CT_ARCH_32=y
+CT_GCC_VERSION=\"14.2.0\"
+# CT_CC_LANG_CXX is not set
+";
+        let diff = |full| ct_config_diff(old, new, full)
+            .into_iter()
+            .map(|(sign, line)| format!("{sign}{line}"))
+            .collect::<Vec<_>>();
+        assert_eq!(diff(false), vec![
+            "+CT_ARCH_32=y",
+            "-CT_ARCH_64=y",
+            "-CT_GCC_VERSION=\"15.1.0\"",
+            "+CT_GCC_VERSION=\"14.2.0\"",
+        ]);
+        assert_eq!(diff(true).len(), 6);
+        assert!(diff(true).contains(&" # CT_CC_LANG_CXX is not set".to_string()));
+    }
+
+    #[test]
+    fn env_assignments() {
+        assert_eq!(parse_env_assignment("CT_ALLOW_BUILD_AS_ROOT=y").unwrap(), ("CT_ALLOW_BUILD_AS_ROOT".to_string(), "y".to_string()));
+        assert_eq!(parse_env_assignment("_X=a=b").unwrap(), ("_X".to_string(), "a=b".to_string()));
+        assert!(parse_env_assignment("CT_ALLOW_BUILD_AS_ROOT").is_err());
+        assert!(parse_env_assignment("=y").is_err());
+        assert!(parse_env_assignment("1CT=y").is_err());
+    }
+
+    #[test]
+    fn gcc_config_extra() {
+        let cfg = config();
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.gcc_config_extra = vec!["--with-isl".into(), "--with-pkgversion=\"chained\"".into()];
+        assert!(t.crosstool_config(&cfg).contains(
+            "CT_CC_GCC_EXTRA_CONFIG_ARRAY=\"--enable-host-shared --disable-bootstrap --with-isl --with-pkgversion=\\\"chained\\\"\"\n"
+        ));
+    }
+
+    #[test]
+    fn binutils_extra_config() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        assert!(!t.crosstool_config(&config()).contains("CT_BINUTILS_EXTRA_CONFIG_ARRAY"));
+        t.binutils_extra_config = vec!["--enable-targets=all".into(), "--enable-gold".into()];
+        assert!(t.crosstool_config(&config()).contains("CT_BINUTILS_EXTRA_CONFIG_ARRAY=\"--enable-targets=all --enable-gold\"\n"));
+    }
+
+    #[test]
+    fn threads_default_per_os() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        assert!(t.crosstool_config(&config()).contains("CT_THREADS=\"posix\"\n"));
+        t.threads = Some(GccThreads::None);
+        assert!(t.crosstool_config(&config()).contains("CT_THREADS=\"none\"\n"));
+
+        let t = toolchain("m68k-unknown-elf");
+        assert!(t.crosstool_config(&config()).contains("CT_THREADS=\"none\"\n"));
+    }
+
+    #[test]
+    fn cxx_abi_defaults_to_libstdcxx() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        assert_eq!(t.cxx_abi(), CxxAbi::Libstdcxx);
+        assert!(!t.crosstool_config(&config()).contains("CT_CC_LANG_CXX"));
+        t.cxx_abi = Some(CxxAbi::Libcxx);
+        assert_eq!(toml::Value::try_from(&t).unwrap()["cxx_abi"].as_str(), Some("libc++"));
+        assert_eq!(<CxxAbi as clap::ValueEnum>::from_str("libstdc++", false), Ok(CxxAbi::Libstdcxx));
+    }
+
+    #[test]
+    fn target_alias() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        assert_eq!(t.gcc_binary(), Path::new("/chained/base/prefix/bin/aarch64-unknown-linux-gnu-gcc"));
+        t.target_alias = Some("aarch64-linux".into());
+        assert_eq!(t.gcc_binary(), Path::new("/chained/base/prefix/bin/aarch64-linux-gcc"));
+        assert!(t.crosstool_config(&config()).contains("CT_TARGET_ALIAS=\"aarch64-linux\"\n"));
+    }
+
+    #[test]
+    fn hardening_cflags() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        assert!(!t.crosstool_config(&config()).contains("CT_TARGET_CFLAGS"));
+        t.fortify_source = Some(2);
+        t.stack_protector = Some(StackProtector::Strong);
+        t.target_cflags = Some("-O3".into());
+        assert!(t.crosstool_config(&config()).contains("CT_TARGET_CFLAGS=\"-D_FORTIFY_SOURCE=2 -O2 -fstack-protector-strong -O3\"\n"));
+        t.target_opt_cflags = Some("-mtune=cortex-a53".into());
+        assert!(t.crosstool_config(&config()).contains("CT_TARGET_CFLAGS=\"-D_FORTIFY_SOURCE=2 -O2 -fstack-protector-strong -O3 -mtune=cortex-a53\"\n"));
+    }
+
+    #[test]
+    fn lto() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.lto_enabled = true;
+        let ct_config = t.crosstool_config(&config());
+        assert!(ct_config.contains("CT_CFLAGS_FOR_HOST=\"-O2 -flto\"\n"));
+        assert!(ct_config.contains("CT_CC_GCC_EXTRA_CONFIG_ARRAY=\"--enable-host-shared --disable-bootstrap --enable-lto\"\n"));
+        t.debug_build = true;
+        assert!(t.crosstool_config(&config()).contains("CT_CFLAGS_FOR_HOST=\"-g -O0 -flto\"\n"));
+    }
+
+    #[test]
+    fn local_patch_dir() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.local_patch_dir = Some("/nonexistent/patches".into());
+        assert!(t.crosstool_config(&config()).contains("CT_LOCAL_PATCH_DIR=\"/nonexistent/patches\"\n"));
+        assert!(t.check_local_patch_dir().is_err());
+        t.local_patch_dir = Some(std::env::temp_dir());
+        assert!(t.check_local_patch_dir().is_ok());
+    }
+
+    #[test]
+    fn sanitizers() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.sanitizers = vec![Sanitizer::Thread];
+        let ct_config = t.crosstool_config(&config());
+        assert!(ct_config.contains("CT_CC_GCC_LIBSANITIZER=y\n"));
+        assert!(ct_config.contains("CT_CC_GCC_EXTRA_CONFIG_ARRAY=\"--enable-host-shared --disable-bootstrap --enable-libsanitizer\"\n"));
+    }
+
+    #[test]
+    fn crosstool_config_hash() {
+        let cfg = config();
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        let hash = t.crosstool_config_hash(&cfg);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, t.crosstool_config_hash(&cfg));
+        t.gcc_src = "https://example.com/gcc.git".into();
+        assert_ne!(hash, t.crosstool_config_hash(&cfg));
+    }
+
+    #[test]
+    fn supported_bits() {
+        let mut t = toolchain("x86_64-unknown-linux-gnu");
+        assert_eq!(t.supported_bits(), [64]);
+        t.multilib = true;
+        assert_eq!(t.supported_bits(), [32, 64]);
+        assert_eq!(toolchain("m68k-unknown-elf").supported_bits(), [32]);
+    }
+
+    #[test]
+    fn glibc_locales_and_nptl() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.libc_locales = Some(false);
+        t.libc_extra_config = vec!["--enable-kernel=5.4".into()];
+        assert!(t.crosstool_config(&config()).contains("CT_LIBC_GLIBC_EXTRA_CONFIG_ARRAY=\"--disable-nls --enable-kernel=5.4\"\n"));
+        t.nptl = false;
+        assert!(t.crosstool_config(&config()).contains("CT_LIBC_GLIBC_EXTRA_CONFIG_ARRAY=\"--disable-nls --without-nptl --enable-kernel=5.4\"\n"));
+
+        let mut t = toolchain("aarch64-unknown-linux-musl");
+        t.libc_locales = Some(false);
+        assert!(!t.crosstool_config(&config()).contains("EXTRA_CONFIG_ARRAY=\"--disable"));
+    }
+
+    #[test]
+    fn debug_pause_steps() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.debug_pause_steps = vec!["cc_core".into(), "libc_main".into()];
+        let ct_config = t.crosstool_config(&config());
+        assert!(ct_config.contains("CT_DEBUG_CT=y\nCT_DEBUG_PAUSE_STEPS=\"cc_core libc_main\"\nCT_DEBUG_PAUSE_STEPS_SHELL=y\n"));
+        assert!(!ct_config.contains("CT_DEBUG_CT_SAVE_STEPS"));
+    }
+
+    #[test]
+    fn canadian_cross() {
+        let mut t = toolchain("m68k-unknown-linux-gnu");
+        t.host_triple = Some("aarch64-unknown-linux-gnu".parse().unwrap());
+        t.host_sysroot = Some("/sysroots/aarch64".into());
+        assert!(t.crosstool_config(&config()).contains("\
+CT_CANADIAN=y
+CT_HOST=\"aarch64-unknown-linux-gnu\"
+CT_HOST_SYSROOT=\"/sysroots/aarch64\"
+CT_HOST_PKG_CONFIG_PATH=\"/sysroots/aarch64/usr/lib/pkgconfig\"
+"));
+    }
+
+    #[test]
+    fn sdk_env_script() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.custom_env = vec![("CFLAGS".into(), "-I/chained/base/prefix/include -DX=\"$y\"".into())];
+
+        let script = t.sdk_env_script("$SDK_DIR/sysroots/aarch64-unknown-linux-gnu", true).unwrap();
+        assert!(script.contains("SDK_DIR=\"$(cd"));
+        assert!(script.contains("export PATH=\"$SDK_DIR/sysroots/aarch64-unknown-linux-gnu/bin:$PATH\"\n"));
+        assert!(script.contains("export CFLAGS=\"-I$SDK_DIR/sysroots/aarch64-unknown-linux-gnu/include -DX=\\\"\\$y\\\"\"\n"));
+
+        let script = t.sdk_env_script("/chained/base/prefix", false).unwrap();
+        assert!(!script.contains("SDK_DIR"));
+        assert!(script.contains("export QEMU_LD_PREFIX=\"/chained/base/prefix/aarch64-unknown-linux-gnu/sysroot\"\n"));
+    }
+
+    #[test]
+    fn kernel_versions() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        assert_eq!(t.kernel_versions(), None);
+        t.kernel_headers_version = Some("6.6".into());
+        assert_eq!(t.kernel_versions(), Some(("6.6", "6.6")));
+        t.kernel_version = Some("6.12".into());
+        assert_eq!(t.kernel_versions(), Some(("6.12", "6.6")));
+
+        let mut t = toolchain("m68k-unknown-elf");
+        t.kernel_version = Some("6.12".into());
+        assert_eq!(t.kernel_versions(), None);
+    }
+
+    #[test]
+    fn env_formats() {
+        let env = HashMap::from([
+            ("CC".to_string(), "aarch64-unknown-linux-gnu-gcc".to_string()),
+            ("CFLAGS".to_string(), r"-DX='a\b'".to_string()),
+        ]);
+        assert_eq!(format_env(&env, EnvFormat::Posix), r#"export CC='aarch64-unknown-linux-gnu-gcc'
+export CFLAGS='-DX='\''a\b'\'''
+"#);
+        assert_eq!(format_env(&env, EnvFormat::Bash), format_env(&env, EnvFormat::Posix));
+        assert_eq!(format_env(&env, EnvFormat::Fish), r#"set -gx CC 'aarch64-unknown-linux-gnu-gcc'
+set -gx CFLAGS '-DX=\'a\\b\''
+"#);
+        let json: HashMap<String, String> = serde_json::from_str(&format_env(&env, EnvFormat::Json)).unwrap();
+        assert_eq!(json, env);
+    }
+
+    #[test]
+    fn expected_tarballs() {
+        assert_eq!(toolchain("aarch64-unknown-linux-musl").expected_tarballs(), ["gcc-", "binutils-", "gmp-", "mpfr-", "mpc-", "linux-", "musl-"]);
+        assert_eq!(toolchain("m68k-unknown-elf").expected_tarballs(), ["gcc-", "binutils-", "gmp-", "mpfr-", "mpc-"]);
+    }
+
+    #[test]
+    fn host_lib_versions() {
+        assert_eq!(parse_host_lib_version("zlib=1.3.1").unwrap(), ("zlib".to_string(), "1.3.1".to_string()));
+        assert!(parse_host_lib_version("openssl=3.0").is_err());
+        assert!(parse_host_lib_version("expat=").is_err());
+        assert!(parse_host_lib_version("expat").is_err());
+
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.host_lib_versions = HashMap::from([
+            ("zlib".to_string(), "1.3.1".to_string()),
+            ("expat".to_string(), "2.6.2".to_string()),
+        ]);
+        assert!(t.crosstool_config(&config()).contains("CT_EXPAT_VERSION=\"2.6.2\"\nCT_ZLIB_VERSION=\"1.3.1\"\n"));
+    }
+
+    #[test]
+    fn list_steps() {
+        let output = "\
+Available build steps, in order:
+  - companion_tools_for_build
+  - binutils_for_host
+  - cc_core
+  - libc_main
+Use \"<step>\" as action to execute only that step.
+Use \"+<step>\" as action to execute up to that step.
+";
+        assert_eq!(parse_list_steps(output), ["companion_tools_for_build", "binutils_for_host", "cc_core", "libc_main"]);
+    }
+
+    #[test]
+    fn ct_ng_killed_by_signal() {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        assert!(check_ct_ng_status("build", ExitStatus::from_raw(0)).is_ok());
+        let err = check_ct_ng_status("build", ExitStatus::from_raw(1 << 8)).unwrap_err();
+        assert_eq!(err.to_string(), "ct-ng build exited with a non-zero status code 1");
+        let err = check_ct_ng_status("build", ExitStatus::from_raw(9)).unwrap_err();
+        assert_eq!(err.to_string(), "ct-ng build killed by signal 9 (SIGKILL)");
+    }
+
+    #[test]
+    fn set_field_checks_keys() {
+        let mut cfg = config();
+        cfg.toolchain.push(toolchain("aarch64-unknown-linux-gnu"));
+
+        cfg.set_field("toolchain.0.ct_jobs", "4").unwrap();
+        assert_eq!(cfg.toolchain[0].ct_jobs, Some(4));
+        cfg.set_field("toolchain.0.target_alias", "arm64-linux").unwrap();
+        assert_eq!(cfg.toolchain[0].target_alias.as_deref(), Some("arm64-linux"));
+        cfg.set_field("toolchain.0.gdb_enabled", "false").unwrap();
+        assert!(!cfg.toolchain[0].gdb_enabled);
+
+        assert!(cfg.set_field("bogus_key", "hello").is_err());
+        assert!(cfg.set_field("toolchain.0.gcc_srcc", "hello").is_err());
+        assert!(cfg.set_field("toolchain.0.ct_jobs", "many").is_err());
+    }
+
+    #[test]
+    fn sample_overrides_survive() {
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.offline = true;
+        t.gdb_enabled = false;
+        let sample = "CT_PREFIX_DIR=\"${CT_PREFIX:-${HOME}/x-tools}/${CT_HOST:+HOST-${CT_HOST}/}${CT_TARGET}\"\nCT_GCC_SRC_RELEASE=y\nCT_LOCAL_TARBALLS_DIR=\"${HOME}/src\"";
+        let config = t.apply_sample_overrides(sample.to_string(), &config()).unwrap();
+        // The last assignment wins when ct-ng reads the config
+        let opts = super::parse_ct_config(&config);
+        assert_eq!(opts["CT_PREFIX_DIR"], Some("\"/chained/base/prefix\""));
+        assert_eq!(opts["CT_LOCAL_TARBALLS_DIR"], Some("\"/chained/cache\""));
+        assert_eq!(opts["CT_GCC_SRC_DEVEL"], Some("y"));
+        assert_eq!(opts["CT_GCC_DEVEL_URL"], Some("\"https://github.com/rust-lang/gcc.git\""));
+        assert_eq!(opts["CT_FORBID_DOWNLOAD"], Some("y"));
+        assert_eq!(opts["CT_DEBUG_GDB"], Some("n"));
+        // The sample picks the target
+        assert!(!opts.contains_key("CT_ARCH_ARM"));
+    }
+
+    #[test]
+    fn remove_spares_foreign_dirs() {
+        use std::path::PathBuf;
+        let defaults = vec![PathBuf::from("/home/u/.cache/chained"), PathBuf::from("/home/u/.local/share/chained")];
+        let mut cfg = config();
+        cfg.data_dir = "/home/u/work".into();
+        let mut t = toolchain("aarch64-unknown-linux-gnu");
+        t.basedir = "/home/u/work/aarch64-unknown-linux-gnu".into();
+        t.prefix = "/home/u/work/aarch64-unknown-linux-gnu/prefix".into();
+        cfg.toolchain.push(t);
+
+        let dirs = super::removable_dirs(defaults.clone(), Some(&cfg));
+        assert!(!dirs.contains(&PathBuf::from("/home/u/work")));
+        assert!(!dirs.contains(&PathBuf::from("/chained/cache")));
+        assert!(dirs.contains(&PathBuf::from("/home/u/work/aarch64-unknown-linux-gnu")));
+        assert!(!dirs.contains(&PathBuf::from("/home/u/work/aarch64-unknown-linux-gnu/prefix")));
+        assert_eq!(super::removable_dirs(defaults.clone(), None), defaults);
+    }
+
+    #[test]
+    fn find_toolchain_mut() {
+        let mut cfg = config();
+        cfg.toolchain.push(toolchain("aarch64-unknown-linux-gnu"));
+        cfg.toolchain.push(toolchain("m68k-unknown-elf"));
+
+        let m68k: Triple = "m68k-unknown-elf".parse().unwrap();
+        cfg.find_toolchain_mut(&m68k).unwrap().ct_jobs = Some(2);
+        assert_eq!(cfg.find_toolchain(&m68k).unwrap().ct_jobs, Some(2));
+        assert!(cfg.find_toolchain_mut(&"sh3-unknown-elf".parse().unwrap()).is_none());
+        assert_eq!(cfg.iter_toolchains().count(), 2);
+    }
+
+    #[test]
+    fn shell_prompts() {
+        assert!(matches!(ShellKind::detect("/usr/bin/bash"), ShellKind::Posix));
+        assert!(matches!(ShellKind::detect("/usr/bin/fish"), ShellKind::Fish));
+        assert!(matches!(ShellKind::detect("/opt/microsoft/powershell/7/pwsh"), ShellKind::PowerShell));
+
+        let (var, args) = ShellKind::Posix.prompt_setup("[m68k]");
+        let var = var.unwrap();
+        assert!(var.contains(r#"CHAINED_PROMPT="[m68k]"; PS1="\${CHAINED_PROMPT} $PS1""#), "{var}");
+        assert!(args.is_empty());
+
+        let (var, _) = ShellKind::Posix.prompt_setup(r#"[$(rm -rf ~) `id` "\"]"#);
+        assert!(var.unwrap().contains(r#"CHAINED_PROMPT="[\$(rm -rf ~) \`id\` \"\\\"]";"#));
+
+        let (var, args) = ShellKind::Fish.prompt_setup("[it's]");
+        assert_eq!(var, None);
+        assert_eq!(args, ["-C", r"function fish_right_prompt; echo '[it\'s]'; end"]);
+
+        let (var, args) = ShellKind::PowerShell.prompt_setup("[it's]");
+        assert_eq!(var, None);
+        assert_eq!(args, ["-NoExit", "-Command", "$chained_prompt = $function:prompt; function prompt { '[it''s] ' + (& $chained_prompt) }"]);
+    }
+
+    #[test]
+    fn build_line_colors() {
+        assert_eq!(colorize_build_line("[ERROR]    configure: error: no acceptable C compiler"), "\x1b[31m[ERROR]    configure: error: no acceptable C compiler\x1b[0m");
+        assert_eq!(colorize_build_line("[WARN ]  Overriding CT_LOCAL_TARBALLS_DIR"), "\x1b[33m[WARN ]  Overriding CT_LOCAL_TARBALLS_DIR\x1b[0m");
+        assert_eq!(colorize_build_line("[INFO ]  Installing final gcc compiler"), "\x1b[32m[INFO ]  Installing final gcc compiler\x1b[0m");
+        assert_eq!(colorize_build_line("[EXTRA]    Configuring gcc"), "[EXTRA]    Configuring gcc");
+    }
+
+    #[test]
+    fn migrate_unversioned_config() {
+        let old: toml::Value = toml::from_str(r#"
+cache_dir = "/chained/cache"
+data_dir = "/chained"
+
+[[toolchain]]
+gcc_src = "https://github.com/rust-lang/gcc.git"
+basedir = "/chained/base"
+json_spec = "/chained/base/target.json"
+prefix = "/chained/base/prefix"
+triple = { arch = "M68k", vendor = "unknown", os = { Linux = "Gnu" } }
+"#).unwrap();
+        let (cfg, changes) = Config::migrate(old).unwrap();
+        assert_eq!(cfg.schema_version, super::SCHEMA_VERSION);
+        assert_eq!(cfg.toolchain[0].triple.to_string(), "m68k-unknown-linux-gnu");
+        assert!(changes.contains(&"added schema_version = 1".to_string()), "{changes:?}");
+        assert!(changes.contains(&"added toolchain.0.gdb_enabled = true".to_string()), "{changes:?}");
+        assert!(changes.iter().any(|c| c.starts_with("changed toolchain.0.triple: ")), "{changes:?}");
+
+        let current = toml::Value::try_from(&cfg).unwrap();
+        assert_eq!(Config::migrate(current).unwrap().1, Vec::<String>::new());
+
+        let future: toml::Value = toml::from_str("schema_version = 99\ncache_dir = \"/c\"\ndata_dir = \"/d\"\ntoolchain = []\n").unwrap();
+        assert!(Config::migrate(future).is_err());
+    }
+}
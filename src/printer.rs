@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::{Config, Toolchain};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Toml,
+}
+
+impl OutputFormat {
+    pub fn printer(self) -> Box<dyn Printer> {
+        match self {
+            Self::Text => Box::new(TextPrinter),
+            Self::Json => Box::new(JsonPrinter),
+            Self::Toml => Box::new(TomlPrinter),
+        }
+    }
+}
+
+pub trait Printer {
+    fn print_config(&self, cfg: &Config, path: &Path) -> Result<()>;
+    fn print_toolchains(&self, toolchains: &[Toolchain], verbose: bool) -> Result<()>;
+    fn print_toolchain(&self, t: &Toolchain) -> Result<()>;
+    fn print_env(&self, env: &HashMap<String, String>) -> Result<()>;
+}
+
+pub struct TextPrinter;
+
+impl Printer for TextPrinter {
+    fn print_config(&self, cfg: &Config, path: &Path) -> Result<()> {
+        println!("Read config from {}", path.display());
+        println!("Cache directory: {}", cfg.cache_dir.display());
+        println!("Data directory: {}", cfg.data_dir.display());
+        for tgt in cfg.iter_toolchains() {
+            println!();
+            println!("Toolchain triple {}:", tgt.triple);
+            println!("\tJSON target specification path: {}", tgt.json_spec.display());
+            println!("\tbase directory path: {}", tgt.basedir.display());
+            println!("\tprefix path: {}", tgt.prefix.display());
+        }
+        Ok(())
+    }
+    fn print_toolchains(&self, toolchains: &[Toolchain], verbose: bool) -> Result<()> {
+        for tgt in toolchains {
+            if verbose {
+                println!("{} {} - {}", tgt.triple, tgt.status(), tgt.triple.description());
+            } else {
+                println!("{} {}", tgt.triple, tgt.status());
+            }
+        }
+        Ok(())
+    }
+    fn print_toolchain(&self, t: &Toolchain) -> Result<()> {
+        println!("Toolchain triple {}:", t.triple);
+        println!("\t{}", t.triple.description());
+        if let Some(build) = &t.build_triple {
+            println!("\tbuild triple: {}", build);
+        }
+        if let Some(host) = &t.host_triple {
+            println!("\thost triple: {}", host);
+        }
+        if let Some(sysroot) = &t.host_sysroot {
+            println!("\thost sysroot: {}", sysroot.display());
+        }
+        println!("\tStatus: {}", t.status());
+        println!("\tJSON target specification path: {}", t.json_spec.display());
+        println!("\tbase directory path: {}", t.basedir.display());
+        println!("\tprefix path: {}", t.prefix.display());
+        for (name, path) in t.derived_paths() {
+            let exists = if path.exists() { "exists" } else { "missing" };
+            println!("\t{}: {} ({})", name, path.display(), exists);
+        }
+        println!("\tGCC source: {}", t.gcc_src);
+        if let Some(src) = &t.binutils_src {
+            println!("\tbinutils source: {}", src);
+        }
+        if let Some(src) = &t.glibc_src {
+            println!("\tglibc source: {}", src);
+        }
+        if t.cxx_enabled {
+            println!("\tC++ support: yes, {}", t.cxx_abi());
+        } else {
+            println!("\tC++ support: no");
+        }
+        println!("\tFortran support: {}", if t.fortran_enabled { "yes" } else { "no" });
+        if let Some(cflags) = &t.target_cflags {
+            println!("\ttarget CFLAGS: {}", cflags);
+        }
+        if let Some(cflags) = &t.target_opt_cflags {
+            println!("\ttarget optimization CFLAGS: {}", cflags);
+        }
+        if let Some(ldflags) = &t.target_ldflags {
+            println!("\ttarget LDFLAGS: {}", ldflags);
+        }
+        if let Some(n) = t.parallel_downloads {
+            println!("\tparallel downloads: {}", n);
+        }
+        if let Some(level) = &t.ct_log_level {
+            println!("\tcrosstool-ng log level: {}", level);
+        }
+        if let Some(mirror) = &t.ct_mirror {
+            println!("\tmirror: {}", mirror);
+        }
+        for fragment in t.ct_config_fragments.iter() {
+            println!("\tconfig fragment: {}", fragment.display());
+        }
+        match (&t.gdb_version, t.gdb_enabled) {
+            (_, false) => println!("\tGDB: disabled"),
+            (Some(version), true) => println!("\tGDB version: {}", version),
+            (None, true) => println!("\tGDB version: crosstool-ng default"),
+        }
+        if t.strace_enabled {
+            println!("\tstrace version: {}", t.strace_version.as_deref().unwrap_or("crosstool-ng default"));
+        }
+        if let Some(downloader) = t.downloader {
+            println!("\tdownloader: {}", downloader.to_string().to_lowercase());
+        }
+        if let Some(jobs) = t.ct_jobs {
+            println!("\tct-ng make jobs: {}", jobs);
+        }
+        if t.save_steps {
+            println!("\tstep-by-step builds: yes");
+        }
+        if let Some(overlay) = &t.ct_overlay {
+            println!("\toverlay: {}", overlay.display());
+        }
+        if !t.gcc_config_extra.is_empty() {
+            println!("\textra GCC configure flags: {}", t.gcc_config_extra.join(" "));
+        }
+        if !t.libc_extra_config.is_empty() {
+            println!("\textra libc configure flags: {}", t.libc_extra_config.join(" "));
+        }
+        if let Some((kernel, headers)) = t.kernel_versions() {
+            println!("\tLinux version: {} (headers {})", kernel, headers);
+        }
+        if t.debug_build {
+            println!("\tdebug build: yes");
+        }
+        if t.offline {
+            println!("\toffline: yes");
+        }
+        let mut host_libs: Vec<_> = t.host_lib_versions.iter().collect();
+        host_libs.sort();
+        for (lib, version) in host_libs {
+            println!("\t{} version: {}", lib, version);
+        }
+        if !t.binutils_extra_config.is_empty() {
+            println!("\textra binutils configure flags: {}", t.binutils_extra_config.join(" "));
+        }
+        println!("\tthreads: {}", t.threads());
+        if let Some(alias) = &t.target_alias {
+            println!("\ttool prefix: {}-", alias);
+        }
+        if t.static_toolchain {
+            println!("\tstatically linked: yes");
+        }
+        if t.lto_enabled {
+            println!("\tLTO: yes");
+        }
+        if let Some(prefix) = &t.sysroot_dir_prefix {
+            println!("\tsysroot directory prefix: {}", prefix);
+        }
+        if let Some(suffix) = &t.sysroot_suffix {
+            println!("\tsysroot suffix: {}", suffix);
+        }
+        if t.allow_root {
+            println!("\tbuild as root: yes");
+        }
+        if let Some(dir) = &t.local_patch_dir {
+            println!("\tlocal patch directory: {}", dir.display());
+        }
+        if t.graphite_enabled {
+            println!("\tGraphite: yes, ISL {}", t.isl_version.as_deref().unwrap_or("crosstool-ng default"));
+        }
+        if !t.sanitizers.is_empty() {
+            let names: Vec<_> = t.sanitizers.iter().map(|s| s.to_string()).collect();
+            println!("\tsanitizers: {}", names.join(", "));
+        }
+        if t.multilib {
+            println!("\tmultilib: yes");
+        }
+        if let Some(locales) = t.libc_locales {
+            println!("\tlibc locales: {}", if locales { "yes" } else { "no" });
+        }
+        if !t.nptl && t.triple.is_glibc() {
+            println!("\tNPTL: no");
+        }
+        if t.strip_host {
+            println!("\tstripped executables: yes");
+        }
+        if !t.debug_pause_steps.is_empty() {
+            println!("\tpauses before: {}", t.debug_pause_steps.join(", "));
+        }
+        if let Some(sample) = &t.from_sample {
+            println!("\tct-ng sample: {}", sample);
+        }
+        Ok(())
+    }
+    fn print_env(&self, env: &HashMap<String, String>) -> Result<()> {
+        let mut vars: Vec<_> = env.iter().collect();
+        vars.sort();
+        for (k, v) in vars {
+            println!("{k}={v}");
+        }
+        Ok(())
+    }
+}
+
+// The triple split into its parts, so scripts don't have to parse it
+fn triple_parts(t: &Toolchain) -> Value {
+    json!({
+        "arch": t.triple.arch().to_string(),
+        "vendor": t.triple.vendor(),
+        "os": t.triple.os().to_string(),
+    })
+}
+
+fn toolchain_value(t: &Toolchain) -> Result<Value> {
+    let mut value = serde_json::to_value(t)?;
+    value["triple_parts"] = triple_parts(t);
+    for (name, path) in t.derived_paths() {
+        value[name] = json!({
+            "path": path,
+            "exists": path.exists(),
+        });
+    }
+    Ok(value)
+}
+
+// Printers that only differ in how they serialize the same data
+trait StructuredPrinter {
+    fn emit(&self, value: Value) -> Result<()>;
+}
+
+impl<P: StructuredPrinter> Printer for P {
+    fn print_config(&self, cfg: &Config, path: &Path) -> Result<()> {
+        let toolchains = cfg.iter_toolchains()
+            .map(toolchain_value)
+            .collect::<Result<Vec<_>>>()?;
+        self.emit(json!({
+            "path": path,
+            "cache_dir": cfg.cache_dir,
+            "data_dir": cfg.data_dir,
+            "toolchain": toolchains,
+        }))
+    }
+    fn print_toolchains(&self, toolchains: &[Toolchain], _verbose: bool) -> Result<()> {
+        let list: Vec<_> = toolchains.iter()
+            .map(|tgt| json!({
+                "triple": tgt.triple.to_string(),
+                "status": tgt.status(),
+                "description": tgt.triple.description(),
+                "triple_parts": triple_parts(tgt),
+            }))
+            .collect();
+        self.emit(Value::Array(list))
+    }
+    fn print_toolchain(&self, t: &Toolchain) -> Result<()> {
+        self.emit(toolchain_value(t)?)
+    }
+    fn print_env(&self, env: &HashMap<String, String>) -> Result<()> {
+        self.emit(serde_json::to_value(env)?)
+    }
+}
+
+pub struct JsonPrinter;
+
+impl StructuredPrinter for JsonPrinter {
+    fn emit(&self, value: Value) -> Result<()> {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        Ok(())
+    }
+}
+
+pub struct TomlPrinter;
+
+impl StructuredPrinter for TomlPrinter {
+    fn emit(&self, value: Value) -> Result<()> {
+        // TOML has neither null nor top-level arrays
+        let value = match strip_nulls(value) {
+            Value::Array(list) => json!({ "toolchain": list }),
+            v => v,
+        };
+        print!("{}", toml::to_string_pretty(&value)?);
+        Ok(())
+    }
+}
+
+fn strip_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(map.into_iter()
+            .filter(|(_, v)| !v.is_null())
+            .map(|(k, v)| (k, strip_nulls(v)))
+            .collect()),
+        Value::Array(list) => Value::Array(list.into_iter().map(strip_nulls).collect()),
+        v => v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_nulls;
+    use serde_json::json;
+
+    #[test]
+    fn nulls_are_stripped_for_toml() {
+        let value = strip_nulls(json!({
+            "triple": "aarch64-unknown-linux-gnu",
+            "target_cflags": null,
+            "custom_env": [["CC", "gcc"]],
+            "nested": { "a": null, "b": 1 },
+        }));
+        assert_eq!(value, json!({
+            "triple": "aarch64-unknown-linux-gnu",
+            "custom_env": [["CC", "gcc"]],
+            "nested": { "b": 1 },
+        }));
+        assert!(toml::to_string(&value).is_ok());
+    }
+}
@@ -1,10 +1,19 @@
 use bitflags::bitflags;
+use std::str::FromStr;
 use strum::EnumString;
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+// TODO: drop the capitalized aliases in the next major version, they're only
+// there so configs written before endianness was serialized in lowercase
+// still load
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, strum::Display)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
 pub enum Endian {
+    #[serde(alias = "Little")]
     Little,
+    #[serde(alias = "Big")]
     Big,
 }
 
@@ -18,6 +27,7 @@ bitflags! {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub enum X86Variant {
     I386,
     I586,
@@ -27,12 +37,22 @@ pub enum X86Variant {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub enum Arch {
-    // I am not going to parse the clusterfuck of arm32 triples
+    // Only plain arm and armeb, I am not going to parse the clusterfuck of
+    // armv7* triples
+    Arm32(Endian),
     Arm64(Endian),
     M68k,
+    // Xilinx FPGA soft core, always 32-bit
+    MicroBlaze(Endian),
     Mips32(Endian),
     Mips64(Endian),
+    // Intel/Altera FPGA soft core, always 32-bit little endian
+    NiosII,
+    // Always 32-bit big endian, and without a hardware FPU so there are no
+    // FPU options to emit
+    OpenRisc,
     //PowerPc32(Endian),
     Sh3(Endian),
     X86(X86Variant),
@@ -41,24 +61,81 @@ pub enum Arch {
 impl Arch {
     fn endian_cfg(&self) -> &'static str {
         match self {
-            Self::Arm64(e) | Self::Mips32(e) | Self::Mips64(e) | Self::Sh3(e) => {
+            Self::Arm32(e) | Self::Arm64(e) | Self::MicroBlaze(e) | Self::Mips32(e) | Self::Mips64(e) | Self::Sh3(e) => {
                 match e {
                     Endian::Little => "CT_ARCH_LE=y",
                     Endian::Big => "CT_ARCH_BE=y",
                 }
             },
-            Self::M68k | Self::X86(_) => "CT_ARCH_LE=y",
+            Self::M68k | Self::NiosII | Self::X86(_) => "CT_ARCH_LE=y",
+            Self::OpenRisc => "CT_ARCH_BE=y",
         }
     }
     fn bitness_cfg(&self) -> &'static str {
+        if self.bits().contains(Bits::B_64) {
+            "CT_ARCH_64=y"
+        } else {
+            "CT_ARCH_32=y"
+        }
+    }
+    pub fn bits(&self) -> Bits {
+        match self {
+            Self::Arm64(_) | Self::Mips64(_) | Self::X86(X86Variant::X86_64) | Self::X86(X86Variant::X86_64h) => Bits::B_64,
+            Self::Arm32(_) | Self::MicroBlaze(_) | Self::Mips32(_) | Self::Sh3(_) | Self::M68k | Self::NiosII | Self::OpenRisc | Self::X86(_) => Bits::B_32,
+        }
+    }
+    fn rust_arch(&self) -> &'static str {
+        match self {
+            Self::Arm32(_) => "arm",
+            Self::Arm64(_) => "aarch64",
+            Self::M68k => "m68k",
+            Self::MicroBlaze(_) => "microblaze",
+            Self::Mips32(_) => "mips",
+            Self::Mips64(_) => "mips64",
+            Self::NiosII => "nios2",
+            Self::OpenRisc => "or1k",
+            Self::Sh3(_) => "sh",
+            Self::X86(X86Variant::X86_64) | Self::X86(X86Variant::X86_64h) => "x86_64",
+            Self::X86(_) => "x86",
+        }
+    }
+    fn endian(&self) -> Endian {
         match self {
-            Self::Arm64(_) | Self::Mips64(_) | Self::X86(X86Variant::X86_64) | Self::X86(X86Variant::X86_64h) => "CT_ARCH_64=y",
-            Self::Mips32(_) | Self::Sh3(_) | Self::M68k | Self::X86(_) => "CT_ARCH_32=y"
+            Self::Arm32(e) | Self::Arm64(e) | Self::MicroBlaze(e) | Self::Mips32(e) | Self::Mips64(e) | Self::Sh3(e) => e.clone(),
+            Self::M68k | Self::OpenRisc => Endian::Big,
+            Self::NiosII | Self::X86(_) => Endian::Little,
         }
     }
+    // Data layouts as used by rustc's builtin targets, SuperH, OpenRISC,
+    // MicroBlaze and NIOS II have no LLVM backend
+    fn data_layout(&self) -> Option<&'static str> {
+        let layout = match self {
+            Self::Arm32(Endian::Little) => "e-m:e-p:32:32-Fi8-i64:64-v128:64:128-a:0:32-n32-S64",
+            Self::Arm32(Endian::Big) => "E-m:e-p:32:32-Fi8-i64:64-v128:64:128-a:0:32-n32-S64",
+            Self::Arm64(Endian::Little) => "e-m:e-p270:32:32-p271:32:32-p272:64:64-i8:8:32-i16:16:32-i64:64-i128:128-n32:64-S128-Fn32",
+            Self::Arm64(Endian::Big) => "E-m:e-p270:32:32-p271:32:32-p272:64:64-i8:8:32-i16:16:32-i64:64-i128:128-n32:64-S128-Fn32",
+            Self::M68k => "E-m:e-p:32:16:32-i8:8:8-i16:16:16-i32:16:32-n8:16:32-a:0:16-S16",
+            Self::Mips32(Endian::Little) => "e-m:m-p:32:32-i8:8:32-i16:16:32-i64:64-n32-S64",
+            Self::Mips32(Endian::Big) => "E-m:m-p:32:32-i8:8:32-i16:16:32-i64:64-n32-S64",
+            Self::Mips64(Endian::Little) => "e-m:e-i8:8:32-i16:16:32-i64:64-n32:64-S128",
+            Self::Mips64(Endian::Big) => "E-m:e-i8:8:32-i16:16:32-i64:64-n32:64-S128",
+            Self::MicroBlaze(_) | Self::NiosII | Self::Sh3(_) | Self::OpenRisc => return None,
+            Self::X86(X86Variant::X86_64) | Self::X86(X86Variant::X86_64h) => "e-m:e-p270:32:32-p271:32:32-p272:64:64-i64:64-i128:128-f80:128-n8:16:32:64-S128",
+            Self::X86(_) => "e-m:e-p:32:32-p270:32:32-p271:32:32-p272:64:64-i128:128-f64:32:64-f80:32-n8:16:32-S128",
+        };
+        Some(layout)
+    }
+    // Only 32-bit ARM uses the EABI, and ct-ng always builds it for the EABI
+    fn has_eabi(&self) -> bool {
+        matches!(self, Self::Arm32(_))
+    }
     fn parse1(s: &mut &str) -> winnow::Result<Self> {
         dispatch! {ident;
             "m68k" => empty.value(Self::M68k),
+            "microblaze" => empty.value(Self::MicroBlaze(Endian::Big)),
+            "microblazeel" => empty.value(Self::MicroBlaze(Endian::Little)),
+            "arm" => empty.value(Self::Arm32(Endian::Little)),
+            "armeb" => empty.value(Self::Arm32(Endian::Big)),
             "aarch64" => empty.value(Self::Arm64(Endian::Little)),
             "arm64" => empty.value(Self::Arm64(Endian::Little)),
             "aarch64_be" => empty.value(Self::Arm64(Endian::Big)),
@@ -66,21 +143,28 @@ impl Arch {
             "mips" => empty.value(Self::Mips32(Endian::Big)),
             "mips64" => empty.value(Self::Mips64(Endian::Big)),
             "mips64el" => empty.value(Self::Mips64(Endian::Little)),
+            "nios2" => empty.value(Self::NiosII),
             "i386" => empty.value(Self::X86(X86Variant::I386)),
             "i586" => empty.value(Self::X86(X86Variant::I586)),
             "i686" => empty.value(Self::X86(X86Variant::I686)),
             "x86_64" => empty.value(Self::X86(X86Variant::X86_64)),
             "x86_64h" => empty.value(Self::X86(X86Variant::X86_64h)),
             "sh3" => empty.value(Self::Sh3(Endian::Little)),
+            "sh3eb" => empty.value(Self::Sh3(Endian::Big)),
+            "or1k" => empty.value(Self::OpenRisc),
+            "openrisc" => empty.value(Self::OpenRisc),
             _ => fail,
         }.parse_next(s)
     }
     fn emit_crosstool_config(&self, opts: &mut Vec<String>) {
         let arch_cfg = match self {
-            Self::Arm64(_) => "CT_ARCH_ARM=y",
+            Self::Arm32(_) | Self::Arm64(_) => "CT_ARCH_ARM=y",
             Self::Mips32(_) | Self::Mips64(_) => "CT_ARCH_MIPS=y",
             Self::Sh3(_) => "CT_ARCH_SH=y",
             Self::M68k => "CT_ARCH_M68K=y",
+            Self::MicroBlaze(_) => "CT_ARCH_MICROBLAZE=y",
+            Self::NiosII => "CT_ARCH_NIOS2=y",
+            Self::OpenRisc => "CT_ARCH_OPENRISC=y",
             Self::X86(_) => "CT_ARCH_X86=y",
         };
         opts.push(arch_cfg.into());
@@ -92,15 +176,21 @@ impl Arch {
 impl fmt::Display for Arch {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let s = match self {
+            Arch::Arm32(Endian::Little) => "arm",
+            Arch::Arm32(Endian::Big) => "armeb",
             Arch::Arm64(Endian::Little) => "aarch64",
             Arch::Arm64(Endian::Big) => "aarch64_be",
             Arch::M68k => "m68k",
+            Arch::MicroBlaze(Endian::Little) => "microblazeel",
+            Arch::MicroBlaze(Endian::Big) => "microblaze",
             Arch::Mips32(Endian::Little) => "mipsel",
             Arch::Mips32(Endian::Big) => "mips",
             Arch::Mips64(Endian::Little) => "mips64el",
             Arch::Mips64(Endian::Big) => "mips64",
+            Arch::NiosII => "nios2",
+            Arch::OpenRisc => "or1k",
             Arch::Sh3(Endian::Little) => "sh3",
-            Arch::Sh3(Endian::Big) => todo!("sh3 big endian"),
+            Arch::Sh3(Endian::Big) => "sh3eb",
             Arch::X86(v) => match v {
                 X86Variant::I386 => "i386",
                 X86Variant::I586 => "i586",
@@ -114,8 +204,10 @@ impl fmt::Display for Arch {
 }
 
 use winnow::combinator::{empty, dispatch, fail};
+use winnow::error::{ContextError, FromExternalError, ParserError};
 
 #[derive(Debug, Clone, Eq, PartialEq, EnumString, Serialize, Deserialize, strum::Display)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 #[strum(serialize_all = "lowercase")]
 pub enum LinuxLibc {
     Gnu,
@@ -124,12 +216,68 @@ pub enum LinuxLibc {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, EnumString, Serialize, Deserialize, strum::Display)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 #[strum(serialize_all = "lowercase")]
+pub enum BareMetalLibc {
+    Newlib,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(into = "String", try_from = "String")]
 pub enum NoneAbi {
-    Elf,
+    Elf(Option<BareMetalLibc>),
+    // ARM EABI, soft-float and hard-float respectively
+    Eabi,
+    Eabihf,
+}
+
+impl fmt::Display for NoneAbi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Elf(None) => f.write_str("elf"),
+            Self::Elf(Some(libc)) => write!(f, "elf_{}", libc),
+            Self::Eabi => f.write_str("eabi"),
+            Self::Eabihf => f.write_str("eabihf"),
+        }
+    }
+}
+
+impl FromStr for NoneAbi {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // "Elf" is how configs spelled it before NoneAbi carried a libc
+            "elf" | "Elf" => Ok(Self::Elf(None)),
+            "eabi" => Ok(Self::Eabi),
+            "eabihf" => Ok(Self::Eabihf),
+            _ => {
+                let libc = s.strip_prefix("elf_")
+                    .ok_or(strum::ParseError::VariantNotFound)?
+                    .parse()?;
+                Ok(Self::Elf(Some(libc)))
+            },
+        }
+    }
+}
+
+impl From<NoneAbi> for String {
+    fn from(abi: NoneAbi) -> String {
+        abi.to_string()
+    }
+}
+
+impl TryFrom<String> for NoneAbi {
+    type Error = strum::ParseError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, strum::Display)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub enum Os {
     #[strum(to_string = "linux-{0}")]
     Linux(LinuxLibc),
@@ -151,7 +299,13 @@ impl Os {
             Self::None(abi) => {
                 opts.push("CT_KERNEL_BARE_METAL=y".into());
                 match abi {
-                    NoneAbi::Elf => (),
+                    NoneAbi::Elf(None) => (),
+                    NoneAbi::Elf(Some(BareMetalLibc::Newlib)) => opts.push("CT_LIBC_NEWLIB=y".into()),
+                    NoneAbi::Eabi => opts.push("CT_ARCH_ARM_EABI=y".into()),
+                    NoneAbi::Eabihf => {
+                        opts.push("CT_ARCH_ARM_EABI=y".into());
+                        opts.push("CT_ARCH_FLOAT_HW=y".into());
+                    },
                 }
             },
         }
@@ -159,25 +313,64 @@ impl Os {
     fn parse_osabi(os: &str, abiname: &str) -> winnow::Result<Self> {
         match os {
             "linux" => {
-                let libc = abiname.parse().unwrap();
+                let libc = abiname.parse()
+                    .map_err(|e| ContextError::from_external_error(&abiname, e))?;
                 Ok(Self::Linux(libc))
             },
             "none" | "unknown" => {
-                let abi = abiname.parse().unwrap();
+                let abi = abiname.parse()
+                    .map_err(|e| ContextError::from_external_error(&abiname, e))?;
                 Ok(Self::None(abi))
             },
-            _ => panic!(),
+            _ => Err(ContextError::from_input(&os)),
         }
     }
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error)]
+pub enum ValidationWarning {
+    #[error("crosstool-ng cannot build a Linux toolchain for {0}")]
+    UnsupportedLinuxArch(Arch),
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "TripleRepr")]
 pub struct Triple {
     arch: Arch,
     vendor: String,
     os: Os,
 }
 
+// Triples are stored as plain strings, but configs written before that have
+// them as tables of serde's default enum representation
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TripleRepr {
+    String(String),
+    Legacy {
+        arch: Arch,
+        vendor: String,
+        os: Os,
+    },
+}
+
+impl From<Triple> for String {
+    fn from(triple: Triple) -> String {
+        triple.to_string()
+    }
+}
+
+impl TryFrom<TripleRepr> for Triple {
+    type Error = String;
+
+    fn try_from(repr: TripleRepr) -> Result<Self, Self::Error> {
+        match repr {
+            TripleRepr::String(s) => s.parse(),
+            TripleRepr::Legacy { arch, vendor, os } => Ok(Triple { arch, vendor, os }),
+        }
+    }
+}
+
 
 use winnow::token::{one_of, take_while};
 use winnow::Parser;
@@ -192,27 +385,239 @@ pub fn ident<'a>(input: &mut &'a str) -> winnow::Result<&'a str> {
 }
 
 
+const ARCH_DESCRIPTIONS: &[(Arch, &str)] = &[
+    (Arch::Arm32(Endian::Little), "32-bit ARM"),
+    (Arch::Arm32(Endian::Big), "32-bit ARM big-endian"),
+    (Arch::Arm64(Endian::Little), "AArch64 (64-bit ARM)"),
+    (Arch::Arm64(Endian::Big), "AArch64 (64-bit ARM) big-endian"),
+    (Arch::M68k, "Motorola 68000"),
+    (Arch::MicroBlaze(Endian::Little), "MicroBlaze little-endian"),
+    (Arch::MicroBlaze(Endian::Big), "MicroBlaze"),
+    (Arch::Mips32(Endian::Little), "MIPS32 little-endian"),
+    (Arch::Mips32(Endian::Big), "MIPS32 big-endian"),
+    (Arch::Mips64(Endian::Little), "MIPS64 little-endian"),
+    (Arch::Mips64(Endian::Big), "MIPS64 big-endian"),
+    (Arch::NiosII, "NIOS II"),
+    (Arch::OpenRisc, "OpenRISC 1000"),
+    (Arch::Sh3(Endian::Little), "SuperH SH-3"),
+    (Arch::Sh3(Endian::Big), "SuperH SH-3 big-endian"),
+    (Arch::X86(X86Variant::I386), "x86 (i386)"),
+    (Arch::X86(X86Variant::I586), "x86 (i586)"),
+    (Arch::X86(X86Variant::I686), "x86 (i686)"),
+    (Arch::X86(X86Variant::X86_64), "x86-64"),
+    (Arch::X86(X86Variant::X86_64h), "x86-64 (Haswell)"),
+];
+
+const COMMON_TRIPLES: &[&str] = &[
+    "aarch64-unknown-linux-gnu",
+    "aarch64-unknown-linux-musl",
+    "arm-none-eabi",
+    "arm-none-eabihf",
+    "x86_64-unknown-linux-gnu",
+    "x86_64-unknown-linux-musl",
+    "i686-unknown-linux-gnu",
+    "mips-unknown-linux-musl",
+    "mipsel-unknown-linux-musl",
+    "mips64-unknown-linux-gnu",
+    "m68k-unknown-linux-gnu",
+    "microblaze-unknown-linux-gnu",
+    "nios2-unknown-linux-gnu",
+    "or1k-unknown-linux-musl",
+    "sh3-unknown-elf",
+];
+
 impl Triple {
+    // One instance of every Arch variant, for listing what we support
+    pub fn supported_arches() -> Vec<Arch> {
+        vec![
+            Arch::Arm64(Endian::Little),
+            Arch::Arm32(Endian::Little),
+            Arch::M68k,
+            Arch::MicroBlaze(Endian::Big),
+            Arch::Mips32(Endian::Big),
+            Arch::Mips64(Endian::Big),
+            Arch::NiosII,
+            Arch::OpenRisc,
+            Arch::Sh3(Endian::Little),
+            Arch::X86(X86Variant::X86_64),
+        ]
+    }
+    pub fn common_triples() -> Vec<Triple> {
+        COMMON_TRIPLES.iter()
+            .map(|t| t.parse().expect("common triples must parse"))
+            .collect()
+    }
+    // Short human-readable description, e.g. "x86-64 Linux with musl"
+    pub fn description(&self) -> String {
+        let arch = ARCH_DESCRIPTIONS.iter()
+            .find(|(arch, _)| *arch == self.arch)
+            .map(|(_, desc)| desc.to_string())
+            .unwrap_or_else(|| format!("{:?}", self.arch));
+        let os = match &self.os {
+            Os::Linux(LinuxLibc::Gnu) => "Linux with glibc",
+            Os::Linux(LinuxLibc::Musl) => "Linux with musl",
+            Os::Linux(LinuxLibc::Uclibc) => "Linux with uClibc-ng",
+            Os::None(NoneAbi::Elf(None)) => "bare-metal ELF",
+            Os::None(NoneAbi::Elf(Some(BareMetalLibc::Newlib))) => "bare-metal ELF with newlib",
+            Os::None(NoneAbi::Eabi) => "bare-metal EABI",
+            Os::None(NoneAbi::Eabihf) => "bare-metal EABI, hard-float",
+        };
+        format!("{arch} {os}")
+    }
     fn parse(s: &mut &str) -> winnow::Result<Triple> {
         use winnow::combinator::separated;
         let v: Vec<&str> = separated(1.., ident, '-')
             .parse_next(s)?;
 
-        let v = match v.as_slice() {
-            &[mut arch, os, abi] => Triple {
+        let v = match *v.as_slice() {
+            [mut arch, os, abi] => Triple {
                 arch: Arch::parse1(&mut arch)?,
                 vendor: "unknown".into(),
                 os: Os::parse_osabi(os, abi)?,
             },
-            &[mut arch, vendor, os, abi] => Triple {
+            [mut arch, vendor, os, abi] => Triple {
                 arch: Arch::parse1(&mut arch)?,
                 vendor: vendor.to_string(),
                 os: Os::parse_osabi(os, abi)?,
             },
-            _ => todo!("proper errors, invalid triple length or something"),
+            _ => return Err(ContextError::from_input(s)),
         };
+        // Bare-metal ELF and EABI are mutually exclusive, depending on the arch
+        if let Os::None(abi) = &v.os && matches!(abi, NoneAbi::Eabi | NoneAbi::Eabihf) != v.arch.has_eabi() {
+            return Err(ContextError::from_input(s));
+        }
         Ok(v)
     }
+    pub fn bits(&self) -> Bits {
+        self.arch.bits()
+    }
+    // Clang encodes the MIPS64 ABI in the environment, everything else we
+    // support looks the same as the GNU triple
+    pub fn to_clang_target(&self) -> String {
+        match (&self.arch, &self.os) {
+            (Arch::Mips64(_), Os::Linux(libc)) => format!("{}-{}-linux-{}abi64", self.arch, self.vendor, libc),
+            (Arch::Arm32(_), Os::Linux(libc)) => format!("{}-{}-linux-{}eabi", self.arch, self.vendor, libc),
+            _ => self.to_string(),
+        }
+    }
+    pub fn with_bare_metal_libc(&self, libc: BareMetalLibc) -> Option<Triple> {
+        match self.os {
+            Os::None(NoneAbi::Elf(_)) => Some(Triple {
+                os: Os::None(NoneAbi::Elf(Some(libc))),
+                ..self.clone()
+            }),
+            Os::None(NoneAbi::Eabi | NoneAbi::Eabihf) | Os::Linux(_) => None,
+        }
+    }
+    // ct-ng option holding extra configure flags of the C library, if there is one
+    pub fn libc_extra_config_key(&self) -> Option<&'static str> {
+        match self.os {
+            Os::Linux(LinuxLibc::Gnu) => Some("CT_LIBC_GLIBC_EXTRA_CONFIG_ARRAY"),
+            Os::Linux(LinuxLibc::Musl) => Some("CT_LIBC_MUSL_EXTRA_CONFIG_ARRAY"),
+            Os::Linux(LinuxLibc::Uclibc) => Some("CT_LIBC_UCLIBC_NG_EXTRA_CONFIG_ARRAY"),
+            Os::None(NoneAbi::Elf(Some(BareMetalLibc::Newlib))) => Some("CT_LIBC_NEWLIB_EXTRA_CONFIG_ARRAY"),
+            Os::None(_) => None,
+        }
+    }
+    pub fn is_glibc(&self) -> bool {
+        self.os == Os::Linux(LinuxLibc::Gnu)
+    }
+    // Name of the C library project, as used in its tarballs
+    pub fn libc_name(&self) -> Option<&'static str> {
+        match self.os {
+            Os::Linux(LinuxLibc::Gnu) => Some("glibc"),
+            Os::Linux(LinuxLibc::Musl) => Some("musl"),
+            Os::Linux(LinuxLibc::Uclibc) => Some("uClibc-ng"),
+            Os::None(NoneAbi::Elf(Some(BareMetalLibc::Newlib))) => Some("newlib"),
+            Os::None(_) => None,
+        }
+    }
+    pub fn arch(&self) -> &Arch {
+        &self.arch
+    }
+    pub fn vendor(&self) -> &str {
+        &self.vendor
+    }
+    pub fn os(&self) -> &Os {
+        &self.os
+    }
+    pub fn is_linux(&self) -> bool {
+        matches!(self.os, Os::Linux(_))
+    }
+    // The tuple ct-ng names installed tools after, so aliases like arm64
+    // still end up as aarch64-unknown-linux-gnu-gcc. Bare-metal tuples have
+    // neither the "none" nor the C library in them, e.g. m68k-unknown-elf-gcc
+    pub fn to_gnu_triplet(&self) -> String {
+        match &self.os {
+            Os::Linux(libc) if self.arch.has_eabi() => format!("{}-{}-linux-{}eabi", self.arch, self.vendor, libc),
+            Os::Linux(_) => self.to_string(),
+            Os::None(abi) => {
+                let abi = match abi {
+                    NoneAbi::Elf(_) => "elf",
+                    NoneAbi::Eabi => "eabi",
+                    NoneAbi::Eabihf => "eabihf",
+                };
+                format!("{}-{}-{}", self.arch, self.vendor, abi)
+            },
+        }
+    }
+    /// Canonical form used for loose comparisons, ignoring the vendor and
+    /// the bare-metal C library
+    pub fn normalize(&self) -> Triple {
+        let os = match &self.os {
+            Os::None(NoneAbi::Elf(_)) => Os::None(NoneAbi::Elf(None)),
+            os => os.clone(),
+        };
+        Triple {
+            arch: self.arch.clone(),
+            vendor: "unknown".into(),
+            os,
+        }
+    }
+    pub fn validate(&self) -> Result<(), Vec<ValidationWarning>> {
+        let mut warnings = Vec::new();
+        match self.os {
+            // Anything goes on bare metal
+            Os::None(_) => (),
+            Os::Linux(_) => {
+                // x86_64h is an Apple-only subarchitecture
+                if self.arch == Arch::X86(X86Variant::X86_64h) {
+                    warnings.push(ValidationWarning::UnsupportedLinuxArch(self.arch.clone()));
+                }
+            },
+        }
+
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+    pub fn emit_json_spec(&self, spec: &mut serde_json::Map<String, serde_json::Value>) {
+        let (os, env) = match &self.os {
+            Os::Linux(libc) => ("linux", libc.to_string()),
+            Os::None(_) => ("none", String::new()),
+        };
+        let pointer_width = if self.bits().contains(Bits::B_64) { 64 } else { 32 };
+
+        spec.insert("llvm-target".into(), self.to_clang_target().into());
+        spec.insert("arch".into(), self.arch.rust_arch().into());
+        spec.insert("vendor".into(), self.vendor.clone().into());
+        spec.insert("os".into(), os.into());
+        spec.insert("env".into(), env.into());
+        spec.insert("target-endian".into(), self.arch.endian().to_string().into());
+        spec.insert("target-pointer-width".into(), pointer_width.to_string().into());
+        if let Some(layout) = self.arch.data_layout() {
+            spec.insert("data-layout".into(), layout.into());
+        }
+    }
+    pub fn default_pre_link_args(&self) -> Vec<String> {
+        match self.os {
+            Os::None(_) => vec!["-nostartfiles".into(), "-nostdlib".into()],
+            Os::Linux(LinuxLibc::Musl) => vec!["-static".into()],
+            Os::Linux(_) => Vec::new(),
+        }
+    }
     pub fn emit_crosstool_config(&self, opts: &mut Vec<String>) {
         self.arch.emit_crosstool_config(opts);
         opts.push(format!("CT_TARGET_VENDOR={}", self.vendor));
@@ -239,20 +644,34 @@ impl fmt::Display for Triple {
     }
 }
 
-use std::str::FromStr;
 impl FromStr for Triple {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Triple::parse.parse(s)
-            .map_err(|e| e.to_string())
+            .map_err(|e| {
+                let arch = s.split('-').next().unwrap_or_default();
+                if Arch::parse1.parse(arch).is_err() {
+                    let arches: Vec<String> = Triple::supported_arches()
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect();
+                    format!("{e}\nsupported architectures: {}", arches.join(", "))
+                } else {
+                    let examples: Vec<String> = Triple::common_triples()
+                        .iter()
+                        .map(|t| t.to_string())
+                        .collect();
+                    format!("{e}\nexamples: {}", examples.join(", "))
+                }
+            })
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::{Arch, Os, LinuxLibc, Triple, NoneAbi, Endian};
+    use super::{Arch, Os, LinuxLibc, Triple, NoneAbi, BareMetalLibc, Endian, X86Variant, ValidationWarning};
     use std::str::FromStr;
 
     #[test]
@@ -282,7 +701,7 @@ mod tests {
         let m68k_unknown_linux_musl = Triple::new3(Arch::M68k, Os::Linux(LinuxLibc::Musl));
         assert_eq!(m68k_unknown_linux_musl, Triple::from_str("m68k-unknown-linux-musl").unwrap());
 
-        let m68k_unknown_elf = Triple::new3(Arch::M68k, Os::None(NoneAbi::Elf));
+        let m68k_unknown_elf = Triple::new3(Arch::M68k, Os::None(NoneAbi::Elf(None)));
         assert_eq!(m68k_unknown_elf, Triple::from_str("m68k-unknown-elf").unwrap());
     }
 
@@ -303,7 +722,300 @@ mod tests {
 
     #[test]
     fn parse_superh() {
-        let sh3_unknown_elf = Triple::new3(Arch::Sh3(Endian::Little), Os::None(NoneAbi::Elf));
+        let sh3_unknown_elf = Triple::new3(Arch::Sh3(Endian::Little), Os::None(NoneAbi::Elf(None)));
         assert_eq!(sh3_unknown_elf, Triple::from_str("sh3-unknown-elf").unwrap());
     }
+
+    #[test]
+    fn invalid_os_or_abi_is_an_error() {
+        assert!(Triple::from_str("m68k-unknown-windows-gnu").is_err());
+        assert!(Triple::from_str("aarch64-linux-foo").is_err());
+        assert!(Triple::from_str("mips-unknown-none-gnu").is_err());
+        assert!(Triple::from_str("x86_64-unknown-linux-elf").is_err());
+        assert!(Triple::from_str("x86_64-linux").is_err());
+        assert!(Triple::from_str("a-b-c-d-e").is_err());
+    }
+
+    #[test]
+    fn validate_linux_arch() {
+        assert_eq!(Triple::from_str("x86_64-unknown-linux-gnu").unwrap().validate(), Ok(()));
+        assert_eq!(Triple::from_str("x86_64h-unknown-elf").unwrap().validate(), Ok(()));
+        assert_eq!(
+            Triple::from_str("x86_64h-unknown-linux-gnu").unwrap().validate(),
+            Err(vec![ValidationWarning::UnsupportedLinuxArch(Arch::X86(X86Variant::X86_64h))])
+        );
+    }
+
+    #[test]
+    fn normalize_ignores_vendor() {
+        let pc = Triple::from_str("x86_64-pc-linux-gnu").unwrap();
+        let unknown = Triple::from_str("x86_64-linux-gnu").unwrap();
+        assert_ne!(pc, unknown);
+        assert_eq!(pc.normalize(), unknown);
+        assert_eq!(pc.normalize(), unknown.normalize());
+
+        let newlib = Triple::from_str("m68k-unknown-elf_newlib").unwrap();
+        assert_eq!(newlib.normalize(), Triple::from_str("m68k-unknown-elf").unwrap());
+    }
+
+    #[test]
+    fn default_pre_link_args() {
+        let elf = Triple::from_str("m68k-unknown-elf").unwrap();
+        assert_eq!(elf.default_pre_link_args(), vec!["-nostartfiles", "-nostdlib"]);
+
+        let musl = Triple::from_str("aarch64-linux-musl").unwrap();
+        assert_eq!(musl.default_pre_link_args(), vec!["-static"]);
+
+        let gnu = Triple::from_str("aarch64-linux-gnu").unwrap();
+        assert!(gnu.default_pre_link_args().is_empty());
+    }
+
+    #[test]
+    fn parse_openrisc() {
+        let or1k_unknown_linux_musl = Triple::new3(Arch::OpenRisc, Os::Linux(LinuxLibc::Musl));
+        assert_eq!(or1k_unknown_linux_musl, Triple::from_str("or1k-unknown-linux-musl").unwrap());
+        assert_eq!(or1k_unknown_linux_musl, Triple::from_str("openrisc-unknown-linux-musl").unwrap());
+        assert_eq!(or1k_unknown_linux_musl.to_string(), "or1k-unknown-linux-musl");
+
+        let or1k_unknown_elf = Triple::new3(Arch::OpenRisc, Os::None(NoneAbi::Elf(None)));
+        assert_eq!(or1k_unknown_elf, Triple::from_str("or1k-unknown-elf").unwrap());
+    }
+
+    #[test]
+    fn gnu_triplet_is_canonical() {
+        assert_eq!(Triple::from_str("arm64-linux-gnu").unwrap().to_gnu_triplet(), "aarch64-unknown-linux-gnu");
+        assert_eq!(Triple::from_str("x86_64-pc-linux-musl").unwrap().to_gnu_triplet(), "x86_64-pc-linux-musl");
+        assert_eq!(Triple::from_str("m68k-unknown-none-elf").unwrap().to_gnu_triplet(), "m68k-unknown-elf");
+        assert_eq!(Triple::from_str("sh3-unknown-elf_newlib").unwrap().to_gnu_triplet(), "sh3-unknown-elf");
+    }
+
+    #[test]
+    fn endian_toml_roundtrip() {
+        let triple = Triple::new3(Arch::Mips32(Endian::Big), Os::Linux(LinuxLibc::Gnu));
+        let toml = toml::to_string(&triple.arch).unwrap();
+        assert!(toml.contains(r#"Mips32 = "big""#));
+        assert_eq!(toml::from_str::<Arch>(&toml).unwrap(), triple.arch);
+
+        let old = "vendor = \"unknown\"\narch = { Mips32 = \"Big\" }\nos = { Linux = \"Gnu\" }\n";
+        assert_eq!(toml::from_str::<Triple>(old).unwrap(), triple);
+    }
+
+    #[test]
+    fn clang_targets() {
+        for (gnu, clang) in [
+            ("aarch64-unknown-linux-gnu", "aarch64-unknown-linux-gnu"),
+            ("aarch64_be-unknown-linux-musl", "aarch64_be-unknown-linux-musl"),
+            ("m68k-unknown-linux-gnu", "m68k-unknown-linux-gnu"),
+            ("mips-unknown-linux-gnu", "mips-unknown-linux-gnu"),
+            ("mipsel-unknown-linux-musl", "mipsel-unknown-linux-musl"),
+            ("mips64-unknown-linux-gnu", "mips64-unknown-linux-gnuabi64"),
+            ("mips64el-unknown-linux-musl", "mips64el-unknown-linux-muslabi64"),
+            ("mips64-unknown-elf", "mips64-unknown-none-elf"),
+            ("i686-unknown-linux-gnu", "i686-unknown-linux-gnu"),
+            ("x86_64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"),
+            ("x86_64-unknown-elf", "x86_64-unknown-none-elf"),
+        ] {
+            assert_eq!(Triple::from_str(gnu).unwrap().to_clang_target(), clang);
+        }
+    }
+
+    #[test]
+    fn parse_newlib() {
+        let m68k_elf_newlib = Triple::new3(Arch::M68k, Os::None(NoneAbi::Elf(Some(BareMetalLibc::Newlib))));
+        assert_eq!(m68k_elf_newlib, Triple::from_str("m68k-unknown-elf_newlib").unwrap());
+        assert_eq!(m68k_elf_newlib, Triple::from_str("m68k-unknown-none-elf_newlib").unwrap());
+        assert_eq!(m68k_elf_newlib.to_string(), "m68k-unknown-none-elf_newlib");
+
+        let m68k_elf = Triple::from_str("m68k-unknown-elf").unwrap();
+        assert_eq!(m68k_elf.with_bare_metal_libc(BareMetalLibc::Newlib), Some(m68k_elf_newlib));
+        assert_eq!(Triple::from_str("m68k-linux-gnu").unwrap().with_bare_metal_libc(BareMetalLibc::Newlib), None);
+    }
+
+    #[test]
+    fn none_abi_old_toml() {
+        let old = "vendor = \"unknown\"\narch = \"M68k\"\n\n[os]\nNone = \"Elf\"\n";
+        let triple = Triple::new3(Arch::M68k, Os::None(NoneAbi::Elf(None)));
+        assert_eq!(toml::from_str::<Triple>(old).unwrap(), triple);
+        assert_eq!(toml::from_str::<Os>(&toml::to_string(&triple.os).unwrap()).unwrap(), triple.os);
+    }
+
+    #[test]
+    fn supported_and_common_triples() {
+        let arches = Triple::supported_arches();
+        for t in Triple::common_triples() {
+            assert!(arches.iter().any(|a| std::mem::discriminant(a) == std::mem::discriminant(&t.arch)), "{t}");
+            assert_eq!(Triple::from_str(&t.to_string()).unwrap(), t);
+        }
+        assert!(Triple::from_str("riscv64-unknown-linux-gnu").unwrap_err().contains("supported architectures: aarch64, arm, m68k"));
+        assert!(Triple::from_str("aarch64-unknown-linux-bogus").unwrap_err().contains("examples: aarch64-unknown-linux-gnu"));
+    }
+
+    #[test]
+    fn eabi() {
+        for (s, abi) in [("eabi", NoneAbi::Eabi), ("eabihf", NoneAbi::Eabihf)] {
+            let triple = Triple::from_str(&format!("arm-none-{s}")).unwrap();
+            assert_eq!(triple, Triple::new3(Arch::Arm32(Endian::Little), Os::None(abi)));
+            assert_eq!(triple.to_string(), format!("arm-unknown-none-{s}"));
+            assert_eq!(triple.to_gnu_triplet(), format!("arm-unknown-{s}"));
+        }
+
+        let mut opts = Vec::new();
+        Triple::from_str("arm-none-eabi").unwrap().emit_crosstool_config(&mut opts);
+        for opt in ["CT_ARCH_ARM=y", "CT_ARCH_32=y", "CT_ARCH_LE=y", "CT_ARCH_ARM_EABI=y"] {
+            assert!(opts.iter().any(|o| o == opt), "{opt}");
+        }
+        assert!(!opts.iter().any(|o| o == "CT_ARCH_FLOAT_HW=y"));
+
+        let mut opts = Vec::new();
+        Triple::from_str("arm-none-eabihf").unwrap().emit_crosstool_config(&mut opts);
+        assert!(opts.iter().any(|o| o == "CT_ARCH_ARM_EABI=y"));
+        assert!(opts.iter().any(|o| o == "CT_ARCH_FLOAT_HW=y"));
+
+        for s in ["aarch64-none-eabi", "m68k-none-eabi", "mips-unknown-none-eabihf", "arm-none-elf"] {
+            assert!(Triple::from_str(s).is_err(), "{s}");
+        }
+        assert_eq!(Triple::from_str("arm-linux-gnu").unwrap().to_gnu_triplet(), "arm-unknown-linux-gnueabi");
+    }
+
+    #[test]
+    fn descriptions() {
+        assert_eq!(Triple::from_str("aarch64-unknown-linux-gnu").unwrap().description(), "AArch64 (64-bit ARM) Linux with glibc");
+        assert_eq!(Triple::from_str("x86_64-linux-musl").unwrap().description(), "x86-64 Linux with musl");
+        assert_eq!(Triple::from_str("mips-unknown-linux-gnu").unwrap().description(), "MIPS32 big-endian Linux with glibc");
+        assert_eq!(Triple::from_str("m68k-unknown-elf").unwrap().description(), "Motorola 68000 bare-metal ELF");
+    }
+
+    #[test]
+    fn serialized_as_string() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Wrapper {
+            triple: Triple,
+        }
+
+        let w = Wrapper { triple: Triple::from_str("aarch64-unknown-linux-gnu").unwrap() };
+        let s = toml::to_string(&w).unwrap();
+        assert_eq!(s, "triple = \"aarch64-unknown-linux-gnu\"\n");
+        assert_eq!(toml::from_str::<Wrapper>(&s).unwrap(), w);
+
+        let legacy = "\
+[triple]
+vendor = \"unknown\"
+arch = { Arm64 = \"Little\" }
+os = { Linux = \"Gnu\" }
+";
+        assert_eq!(toml::from_str::<Wrapper>(legacy).unwrap(), w);
+
+        let legacy = "triple = { arch = \"M68k\", vendor = \"unknown\", os = { None = \"Elf\" } }";
+        assert_eq!(toml::from_str::<Wrapper>(legacy).unwrap().triple, Triple::from_str("m68k-unknown-elf").unwrap());
+    }
+
+    #[test]
+    fn parse_microblaze() {
+        let microblaze_linux_gnu = Triple::new3(Arch::MicroBlaze(Endian::Big), Os::Linux(LinuxLibc::Gnu));
+        assert_eq!(microblaze_linux_gnu, Triple::from_str("microblaze-unknown-linux-gnu").unwrap());
+        assert_eq!(microblaze_linux_gnu.to_string(), "microblaze-unknown-linux-gnu");
+
+        let microblazeel_elf = Triple::new3(Arch::MicroBlaze(Endian::Little), Os::None(NoneAbi::Elf(None)));
+        assert_eq!(microblazeel_elf, Triple::from_str("microblazeel-unknown-elf").unwrap());
+        assert_eq!(microblazeel_elf.to_string(), "microblazeel-unknown-none-elf");
+
+        let mut opts = Vec::new();
+        microblaze_linux_gnu.emit_crosstool_config(&mut opts);
+        for opt in ["CT_ARCH_MICROBLAZE=y", "CT_ARCH_BE=y", "CT_ARCH_32=y"] {
+            assert!(opts.iter().any(|o| o == opt), "{opt}");
+        }
+    }
+
+    #[test]
+    fn parse_nios2() {
+        let nios2_linux_gnu = Triple::new3(Arch::NiosII, Os::Linux(LinuxLibc::Gnu));
+        assert_eq!(nios2_linux_gnu, Triple::from_str("nios2-unknown-linux-gnu").unwrap());
+        assert_eq!(nios2_linux_gnu.to_string(), "nios2-unknown-linux-gnu");
+
+        let mut opts = Vec::new();
+        nios2_linux_gnu.emit_crosstool_config(&mut opts);
+        for opt in ["CT_ARCH_NIOS2=y", "CT_ARCH_LE=y", "CT_ARCH_32=y"] {
+            assert!(opts.iter().any(|o| o == opt), "{opt}");
+        }
+    }
+
+    // The match has no wildcard arm, so a new Arch variant doesn't compile
+    // until it's also added to the list
+    fn all_arches() -> Vec<Arch> {
+        fn _exhaustive(arch: Arch) {
+            match arch {
+                Arch::Arm32(_) | Arch::Arm64(_) | Arch::M68k | Arch::MicroBlaze(_) | Arch::Mips32(_)
+                    | Arch::Mips64(_) | Arch::NiosII | Arch::OpenRisc | Arch::Sh3(_) | Arch::X86(_) => (),
+            }
+        }
+        let mut arches = vec![Arch::M68k, Arch::NiosII, Arch::OpenRisc];
+        for e in [Endian::Little, Endian::Big] {
+            arches.push(Arch::Arm32(e.clone()));
+            arches.push(Arch::Arm64(e.clone()));
+            arches.push(Arch::MicroBlaze(e.clone()));
+            arches.push(Arch::Mips32(e.clone()));
+            arches.push(Arch::Mips64(e.clone()));
+            arches.push(Arch::Sh3(e));
+        }
+        for v in [X86Variant::I386, X86Variant::I586, X86Variant::I686, X86Variant::X86_64, X86Variant::X86_64h] {
+            arches.push(Arch::X86(v));
+        }
+        arches
+    }
+
+    #[test]
+    fn every_arch_displays_and_parses_back() {
+        for arch in all_arches() {
+            let bare_metal = if arch.has_eabi() { NoneAbi::Eabi } else { NoneAbi::Elf(None) };
+            for os in [Os::Linux(LinuxLibc::Gnu), Os::None(bare_metal)] {
+                let t = Triple::new3(arch.clone(), os);
+                assert_eq!(Triple::from_str(&t.to_string()), Ok(t.clone()), "{t}");
+            }
+        }
+    }
+
+    #[test]
+    fn accessors() {
+        let t = Triple::from_str("mipsel-foo-linux-musl").unwrap();
+        assert_eq!(t.arch(), &Arch::Mips32(Endian::Little));
+        assert_eq!(t.vendor(), "foo");
+        assert_eq!(t.os(), &Os::Linux(LinuxLibc::Musl));
+    }
+
+    #[test]
+    fn display_has_a_single_vendor() {
+        assert_eq!(Triple::from_str("m68k-linux-gnu").unwrap().to_string(), "m68k-unknown-linux-gnu");
+        assert_eq!(Triple::from_str("m68k-unknown-linux-gnu").unwrap().to_string(), "m68k-unknown-linux-gnu");
+        let t = Triple::from_str("aarch64-foo-linux-musl").unwrap().to_string();
+        assert_eq!(t, "aarch64-foo-linux-musl");
+        assert!(!t.contains("unknown"));
+    }
+
+    mod roundtrip {
+        use super::super::{Arch, NoneAbi, Os, Triple};
+        use proptest::prelude::*;
+        use std::str::FromStr;
+
+        fn triple() -> impl Strategy<Value = Triple> {
+            (any::<Arch>(), "[a-z][a-z0-9_]{0,7}", any::<Os>())
+                .prop_map(|(arch, vendor, os)| Triple { arch, vendor, os })
+                .prop_filter("EABI is for 32-bit ARM only", |t| match &t.os {
+                    Os::None(abi) => matches!(abi, NoneAbi::Eabi | NoneAbi::Eabihf) == t.arch.has_eabi(),
+                    Os::Linux(_) => true,
+                })
+        }
+
+        proptest! {
+            #[test]
+            fn display_parses_back(t in triple()) {
+                prop_assert_eq!(Triple::from_str(&t.to_string()), Ok(t));
+            }
+
+            #[test]
+            fn serde_roundtrip(t in triple()) {
+                let json = serde_json::to_string(&t).unwrap();
+                prop_assert_eq!(serde_json::from_str::<Triple>(&json).unwrap(), t);
+            }
+        }
+    }
 }